@@ -0,0 +1,137 @@
+use std::cmp::max;
+use bytes::Bytes;
+use futures_util::{SinkExt, StreamExt};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio_util::codec::Framed;
+use crate::codec::LengthPrefixedCodec;
+use crate::error::{ProxyError, Result};
+use crate::message::{remote_error, Capability, Handshake, Message, Metadata};
+use crate::protocol::{MAX_CONTROL_FRAME_SIZE, MAX_ERROR_FRAME_SIZE, MAX_METADATA_SIZE};
+
+/// The tokio counterpart of `ProxyClient`, for callers that can't block
+pub struct AsyncProxyClient {
+    framed: Framed<TcpStream, LengthPrefixedCodec>,
+    handshake: Handshake,
+    max_response_size: Option<u64>,
+}
+
+impl AsyncProxyClient {
+    /// Opens the connection and performs the Connect/Accept handshake
+    pub async fn connect<A: ToSocketAddrs>(proxy_server_address: A) -> Result<AsyncProxyClient> {
+        let socket = TcpStream::connect(proxy_server_address).await?;
+        let mut client = AsyncProxyClient {
+            framed: Framed::new(socket, LengthPrefixedCodec::default()),
+            handshake: Handshake::legacy(),
+            max_response_size: None,
+        };
+        client.handshake = client.negotiate().await?;
+        Ok(client)
+    }
+
+    /// Same negotiation as `ProxyClient`
+    async fn negotiate(&mut self) -> Result<Handshake> {
+        self.send(&Message::ConnectWith(Handshake::offer())).await?;
+        match Message::from_payload(self.receive(MAX_CONTROL_FRAME_SIZE).await?) {
+            Message::Accept => Ok(Handshake::legacy()),
+            Message::AcceptWith(chosen) => Ok(chosen.choose(&Handshake::offer().capabilities)),
+            Message::Error { code: 401, message } => Err(ProxyError::AuthFailed(message)),
+            response => Err(ProxyError::HandshakeRejected { got: response }),
+        }
+    }
+
+    /// The protocol version and the capabilities agreed on with the proxy
+    pub fn handshake(&self) -> &Handshake {
+        &self.handshake
+    }
+
+    /// Asks the proxy for the given URL and returns the response body
+    pub async fn fetch(&mut self, url: &str) -> Result<Vec<u8>> {
+        Ok(self.fetch_response(url).await?.1)
+    }
+
+    /// Same as `fetch`, also returning the metadata when the proxy sends it
+    pub async fn fetch_response(&mut self, url: &str) -> Result<(Option<Metadata>, Vec<u8>)> {
+        self.send(&Message::Get { url: url.to_owned() }).await?;
+        let max_length = self.max_response_size.unwrap_or(u64::MAX);
+        // The metadata and the errors can be bigger than a small response size limit
+        let frame = self.receive_response(max(max_length, max(MAX_METADATA_SIZE, MAX_ERROR_FRAME_SIZE))).await?;
+        let (metadata, body) = match self.parse_metadata(&frame) {
+            Some(metadata) => (Some(metadata), self.receive_body(max_length).await?),
+            None if frame.len() as u64 > max_length =>
+                return Err(ProxyError::FrameTooLarge { length: frame.len() as u64, max: max_length }),
+            None => (None, frame),
+        };
+        Ok((metadata, self.receive_chunks(body, max_length).await?))
+    }
+
+    /// The frame after the metadata
+    async fn receive_body(&mut self, max_length: u64) -> Result<Vec<u8>> {
+        // From version 2 on it's always the body
+        if !self.handshake.is_legacy() {
+            return self.receive(max_length).await;
+        }
+        let body = self.receive_response(max_length).await?;
+        if let Some(metadata) = self.parse_metadata(&body) {
+            return Err(ProxyError::UnexpectedMessage { got: Message::Metadata(metadata) });
+        }
+        Ok(body)
+    }
+
+    /// With chunked bodies the first chunk is followed by more, until an empty one
+    async fn receive_chunks(&mut self, mut body: Vec<u8>, max_length: u64) -> Result<Vec<u8>> {
+        if !self.handshake.supports(Capability::Chunked) || body.is_empty() {
+            return Ok(body);
+        }
+        loop {
+            let chunk = self.receive(max_length - body.len() as u64).await?;
+            if chunk.is_empty() {
+                return Ok(body);
+            }
+            body.extend_from_slice(&chunk);
+        }
+    }
+
+    /// Responses announcing a bigger body are refused before buffering it
+    pub fn set_max_response_size(&mut self, max_response_size: Option<u64>) {
+        self.max_response_size = max_response_size;
+    }
+
+    /// Ends the session, consuming the client
+    pub async fn bye(mut self) -> Result<()> {
+        self.send(&Message::Bye).await?;
+        let response = Message::from_payload(self.receive(MAX_CONTROL_FRAME_SIZE).await?);
+        if response != Message::Bye {
+            return Err(ProxyError::ByeMismatch { got: response });
+        }
+        Ok(())
+    }
+
+    fn parse_metadata(&self, frame: &[u8]) -> Option<Metadata> {
+        if frame.len() as u64 > MAX_METADATA_SIZE || !self.handshake.supports(Capability::Metadata) {
+            return None;
+        }
+        Metadata::parse(frame)
+    }
+
+    async fn send(&mut self, message: &Message) -> Result<()> {
+        self.framed.send(Bytes::copy_from_slice(&message.payload())).await
+    }
+
+    /// Same rules as `FrameReader::read_response_frame`, big bodies are never error frames
+    async fn receive_response(&mut self, max_length: u64) -> Result<Vec<u8>> {
+        let frame = self.receive(max_length).await?;
+        match remote_error(&frame) {
+            Some(err) if frame.len() as u64 <= MAX_ERROR_FRAME_SIZE
+                && self.handshake.supports(Capability::Errors) => Err(err),
+            _ => Ok(frame),
+        }
+    }
+
+    async fn receive(&mut self, max_length: u64) -> Result<Vec<u8>> {
+        self.framed.codec_mut().max_frame_length = max_length;
+        match self.framed.next().await {
+            Some(frame) => Ok(frame?.to_vec()),
+            None => Err(ProxyError::UnexpectedEof),
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static TEMP_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A file that only appears under its name once it's complete.
+/// Everything is written into a temporary file in the same directory,
+/// which `commit` renames into place. If it's dropped without
+/// committing, the temporary file is removed.
+pub struct AtomicFile {
+    file: Option<File>,
+    temp_path: PathBuf,
+    target_path: PathBuf,
+    /// Only set once the rename succeeded, so a failed commit still removes the temporary file
+    committed: bool,
+}
+
+impl AtomicFile {
+    pub fn create<P: AsRef<Path>>(target_path: P) -> io::Result<AtomicFile> {
+        let target_path = target_path.as_ref().to_path_buf();
+        let file_name = target_path.file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "The target path has no file name"))?;
+        let temp_name = format!(
+            ".{}.{}.{}.part",
+            file_name.to_string_lossy(),
+            process::id(),
+            TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed),
+        );
+        let temp_path = target_path.with_file_name(temp_name);
+        let file = OpenOptions::new().write(true).create_new(true).open(&temp_path)?;
+        Ok(AtomicFile { file: Some(file), temp_path, target_path, committed: false })
+    }
+
+    /// Flushes the content to the disk and renames the file into place
+    pub fn commit(mut self) -> io::Result<()> {
+        let file = self.file.take().expect("The file is only taken here");
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&self.temp_path, &self.target_path)?;
+        self.committed = true;
+        sync_parent_directory(&self.target_path)
+    }
+
+    fn file(&mut self) -> &mut File {
+        self.file.as_mut().expect("The file is only taken in commit")
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file().flush()
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        // Closed first, as open files can't be removed on Windows
+        drop(self.file.take());
+        if !self.committed {
+            // Nothing useful can be done if the removal fails
+            let _ = fs::remove_file(&self.temp_path);
+        }
+    }
+}
+
+/// Makes the rename itself durable, directories can't be synced on Windows
+#[cfg(unix)]
+fn sync_parent_directory(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => File::open(parent)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_parent_directory(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("atomic_file_{}_{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn entries(dir: &Path) -> Vec<String> {
+        let mut names: Vec<_> = fs::read_dir(dir).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn commit_moves_the_content_into_place() {
+        let dir = test_dir("commit");
+        let mut file = AtomicFile::create(dir.join("out.txt")).unwrap();
+        file.write_all(b"hello").unwrap();
+        assert!(!dir.join("out.txt").exists());
+        file.commit().unwrap();
+        assert_eq!(fs::read(dir.join("out.txt")).unwrap(), b"hello");
+        assert_eq!(entries(&dir), vec!["out.txt"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn dropping_without_commit_removes_the_temporary_file() {
+        let dir = test_dir("drop");
+        let mut file = AtomicFile::create(dir.join("out.txt")).unwrap();
+        file.write_all(b"partial").unwrap();
+        assert_eq!(entries(&dir).len(), 1);
+        drop(file);
+        assert!(entries(&dir).is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn failed_commit_removes_the_temporary_file() {
+        let dir = test_dir("failed_commit");
+        // Renaming a file over a non-empty directory fails
+        fs::create_dir_all(dir.join("out.txt").join("taken")).unwrap();
+        let file = AtomicFile::create(dir.join("out.txt")).unwrap();
+        assert!(file.commit().is_err());
+        assert_eq!(entries(&dir), vec!["out.txt"]);
+        assert!(dir.join("out.txt").is_dir());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,111 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use crate::error::Result;
+
+/// The server's request for credentials, sent right after its Accept
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthChallenge {
+    /// Send the shared token as it is, only safe over TLS or a Unix socket
+    Token,
+    /// Prove knowing the token with an HMAC-SHA256 of the nonce, keyed with the token
+    Hmac { nonce: Vec<u8> },
+}
+
+/// The client's answer to the challenge
+#[derive(Clone, PartialEq, Eq)]
+pub enum AuthResponse {
+    Token(String),
+    Hmac(Vec<u8>),
+}
+
+/// The shared token, kept out of the debug output
+#[derive(Clone, PartialEq, Eq)]
+pub struct Credentials {
+    token: String,
+}
+
+impl Credentials {
+    pub fn new(token: &str) -> Credentials {
+        Credentials { token: token.to_owned() }
+    }
+
+    /// The whole file is the token, without the trailing line break
+    pub fn from_file(path: &Path) -> Result<Credentials> {
+        let token = fs::read_to_string(path)?;
+        Ok(Credentials::new(token.trim_end_matches(['\r', '\n'])))
+    }
+
+    pub fn respond(&self, challenge: &AuthChallenge) -> AuthResponse {
+        match challenge {
+            AuthChallenge::Token => AuthResponse::Token(self.token.clone()),
+            AuthChallenge::Hmac { nonce } => AuthResponse::Hmac(self.mac(nonce).finalize().into_bytes().to_vec()),
+        }
+    }
+
+    /// The server side check, both comparisons take the same time whatever the answer
+    pub fn verify(&self, challenge: &AuthChallenge, response: &AuthResponse) -> bool {
+        match (challenge, response) {
+            (AuthChallenge::Token, AuthResponse::Token(token)) =>
+                token.len() == self.token.len()
+                    && token.bytes().zip(self.token.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0,
+            (AuthChallenge::Hmac { nonce }, AuthResponse::Hmac(mac)) => self.mac(nonce).verify_slice(mac).is_ok(),
+            _ => false,
+        }
+    }
+
+    fn mac(&self, nonce: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.token.as_bytes()).expect("HMAC takes keys of any length");
+        mac.update(nonce);
+        mac
+    }
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Credentials(..)")
+    }
+}
+
+impl fmt::Debug for AuthResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthResponse::Token(_) => f.write_str("Token(..)"),
+            AuthResponse::Hmac(mac) => write!(f, "Hmac({})", to_hex(mac)),
+        }
+    }
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub(crate) fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len()).step_by(2)
+        .map(|index| u8::from_str_radix(&hex[index..index + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_right_token_passes() {
+        let server = Credentials::new("secret");
+        let hmac = AuthChallenge::Hmac { nonce: b"nonce".to_vec() };
+        for challenge in [AuthChallenge::Token, hmac.clone()] {
+            assert!(server.verify(&challenge, &Credentials::new("secret").respond(&challenge)));
+            assert!(!server.verify(&challenge, &Credentials::new("secreT").respond(&challenge)));
+            assert!(!server.verify(&challenge, &Credentials::new("secret2").respond(&challenge)));
+        }
+        let other_nonce = AuthChallenge::Hmac { nonce: b"other".to_vec() };
+        assert!(!server.verify(&hmac, &server.respond(&other_nonce)));
+        assert!(!server.verify(&hmac, &server.respond(&AuthChallenge::Token)));
+    }
+}
@@ -0,0 +1,726 @@
+//! Reference implementation of the proxy server, mainly for testing the client.
+//! Serves the requested URLs either from a local directory or by forwarding
+//! them to a plain HTTP upstream.
+//! With the `tls` feature it can also terminate TLS, optionally requiring client certificates.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+use std::path::{Component, Path, PathBuf};
+use std::process;
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use clap::{App, Arg};
+use rust_proxy_tcp_client::protocol::{send_chunks, FrameReader, MAX_CONTROL_FRAME_SIZE};
+#[cfg(unix)]
+use rust_proxy_tcp_client::connect::UNIX_SOCKET_PREFIX;
+#[cfg(feature = "tls")]
+use rust_proxy_tcp_client::tls;
+use rust_proxy_tcp_client::auth::{AuthChallenge, Credentials};
+use rust_proxy_tcp_client::message::{Capability, Handshake};
+use rust_proxy_tcp_client::{Message, Metadata, ProxyError, RequestHead, Result};
+
+/// Request heads only carry a URL and headers, anything bigger is a broken or hostile client
+const MAX_REQUEST_SIZE: u64 = 64 * 1024;
+/// The request bodies are buffered before forwarding them
+const MAX_UPLOAD_SIZE: u64 = 256 * 1024 * 1024;
+
+/// What every connection is handled with
+#[derive(Clone)]
+struct Settings {
+    source: Source,
+    send_metadata: bool,
+    /// The token the clients have to prove knowing, and how
+    auth: Option<(Credentials, AuthMode)>,
+    /// Connections without any frame for this long are closed
+    idle_timeout: Option<Duration>,
+}
+
+#[derive(Clone, Copy)]
+enum AuthMode {
+    Token,
+    Hmac,
+}
+
+/// Where the served content comes from
+#[derive(Clone)]
+enum Source {
+    Directory(PathBuf),
+    Upstream(SocketAddr),
+}
+
+fn main() {
+    let app = App::new("Reference proxy server")
+        .author("Ruben Kostandyan @KoStard")
+        .about("Accepts the client connections and serves the requested URLs \
+                from a local directory or an HTTP upstream")
+        .arg(Arg::with_name("listen")
+            .short("l")
+            .long("listen")
+            .help("The address to listen on, as ip:port or unix:/path/to/socket")
+            .takes_value(true)
+            .default_value("127.0.0.1:9000"))
+        .arg(Arg::with_name("root")
+            .long("root")
+            .help("Directory to serve the URL paths from")
+            .takes_value(true)
+            .required_unless("upstream")
+            .conflicts_with("upstream"))
+        .arg(Arg::with_name("upstream")
+            .long("upstream")
+            .help("Address of the HTTP server to forward the requests to")
+            .takes_value(true))
+        .arg(Arg::with_name("send-metadata")
+            .long("send-metadata")
+            .help("Send the status and headers before each body to version 1 clients too, \
+                   newer clients ask for them in the handshake"))
+        .arg(Arg::with_name("idle-timeout")
+            .long("idle-timeout")
+            .help("Close the connections that send nothing for this many seconds, \
+                   clients keep them open with heartbeats")
+            .takes_value(true)
+            .value_name("SECONDS")
+            .validator(|value| parse_seconds(&value).map(|_| ())))
+        .arg(Arg::with_name("auth-token")
+            .long("auth-token")
+            .help("Require the clients to authenticate with this shared token")
+            .takes_value(true)
+            .env("PROXY_TOKEN")
+            .hide_env_values(true))
+        .arg(Arg::with_name("auth-token-file")
+            .long("auth-token-file")
+            .help("File containing the shared token the clients have to authenticate with")
+            .takes_value(true)
+            .conflicts_with("auth-token"))
+        .arg(Arg::with_name("auth-mode")
+            .long("auth-mode")
+            .help("\"hmac\" only sends a proof of knowing the token, \
+                   \"token\" sends the token itself and is only safe over TLS")
+            .takes_value(true)
+            .possible_values(&["hmac", "token"])
+            .default_value("hmac"));
+    #[cfg(feature = "tls")]
+    let app = app
+        .arg(Arg::with_name("tls-cert")
+            .long("tls-cert")
+            .help("PEM certificate chain, enables TLS")
+            .takes_value(true)
+            .requires("tls-key"))
+        .arg(Arg::with_name("tls-key")
+            .long("tls-key")
+            .help("PEM private key of the certificate")
+            .takes_value(true)
+            .requires("tls-cert"))
+        .arg(Arg::with_name("tls-client-ca")
+            .long("tls-client-ca")
+            .help("PEM CA certificates, when given the clients have to present a certificate signed by them")
+            .takes_value(true)
+            .requires("tls-cert"));
+    let app = app.get_matches();
+    let listen_address = app.value_of("listen").expect("Listen address not provided");
+    let source = match app.value_of("root") {
+        Some(root) => Source::Directory(PathBuf::from(root)),
+        None => {
+            let upstream = app.value_of("upstream").expect("Upstream not provided");
+            match upstream.parse() {
+                Ok(address) => Source::Upstream(address),
+                Err(_) => {
+                    eprintln!("Error: {}", ProxyError::BadAddress(upstream.to_owned()));
+                    process::exit(2);
+                }
+            }
+        }
+    };
+
+    let credentials = match (app.value_of("auth-token-file"), app.value_of("auth-token")) {
+        (Some(path), _) => match Credentials::from_file(Path::new(path)) {
+            Ok(credentials) => Some(credentials),
+            Err(err) => {
+                eprintln!("Error: couldn't read the token: {}", err);
+                process::exit(3);
+            }
+        },
+        (None, token) => token.map(Credentials::new),
+    };
+    let auth_mode = match app.value_of("auth-mode") {
+        Some("token") => AuthMode::Token,
+        _ => AuthMode::Hmac,
+    };
+    let settings = Settings {
+        source,
+        send_metadata: app.is_present("send-metadata"),
+        auth: credentials.map(|credentials| (credentials, auth_mode)),
+        idle_timeout: app.value_of("idle-timeout")
+            .map(|value| parse_seconds(value).expect("Validated by clap")),
+    };
+
+    #[cfg(feature = "tls")]
+    let tls_config = match tls_server_config(&app) {
+        Ok(tls_config) => tls_config,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            process::exit(11);
+        }
+    };
+
+    #[cfg(unix)]
+    if let Some(path) = listen_address.strip_prefix(UNIX_SOCKET_PREFIX) {
+        serve_unix_socket(path, settings);
+        return;
+    }
+
+    let listener = match TcpListener::bind(listen_address) {
+        Ok(listener) => listener,
+        Err(err) => {
+            eprintln!("Error: couldn't listen on {}: {}", listen_address, err);
+            process::exit(3);
+        }
+    };
+    println!("Listening on {}", listen_address);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                eprintln!("Failed accepting a connection: {}", err);
+                continue;
+            }
+        };
+        let settings = settings.clone();
+        #[cfg(feature = "tls")]
+        let tls_config = tls_config.clone();
+        thread::spawn(move || {
+            let peer = stream.peer_addr();
+            if let Err(err) = stream.set_read_timeout(settings.idle_timeout) {
+                eprintln!("Couldn't set the idle timeout for {:?}: {}", peer, err);
+            }
+            #[cfg(feature = "tls")]
+            let result = match tls_config {
+                Some(tls_config) => accept_tls(stream, tls_config)
+                    .and_then(|stream| handle_connection(stream, &settings)),
+                None => handle_connection(stream, &settings),
+            };
+            #[cfg(not(feature = "tls"))]
+            let result = handle_connection(stream, &settings);
+            if let Err(err) = result {
+                eprintln!("Connection with {:?} failed: {}", peer, err);
+            }
+        });
+    }
+}
+
+/// Plain connections only, TLS isn't useful for a co-located client
+#[cfg(unix)]
+fn serve_unix_socket(path: &str, settings: Settings) {
+    let listener = match UnixListener::bind(path) {
+        Ok(listener) => listener,
+        Err(err) => {
+            eprintln!("Error: couldn't listen on {}: {}", path, err);
+            process::exit(3);
+        }
+    };
+    println!("Listening on {}{}", UNIX_SOCKET_PREFIX, path);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                eprintln!("Failed accepting a connection: {}", err);
+                continue;
+            }
+        };
+        let settings = settings.clone();
+        thread::spawn(move || {
+            if let Err(err) = stream.set_read_timeout(settings.idle_timeout) {
+                eprintln!("Couldn't set the idle timeout: {}", err);
+            }
+            if let Err(err) = handle_connection(stream, &settings) {
+                eprintln!("Unix socket connection failed: {}", err);
+            }
+        });
+    }
+}
+
+/// A positive number of seconds that fits into a `Duration`
+fn parse_seconds(value: &str) -> std::result::Result<Duration, String> {
+    value.parse::<f64>()
+        .ok()
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+        .filter(|duration| !duration.is_zero())
+        .ok_or_else(|| format!("{:?} is not a positive number of seconds", value))
+}
+
+#[cfg(feature = "tls")]
+fn tls_server_config(app: &clap::ArgMatches) -> Result<Option<Arc<rustls::ServerConfig>>> {
+    match (app.value_of("tls-cert"), app.value_of("tls-key")) {
+        (Some(cert), Some(key)) =>
+            server_tls_config(Path::new(cert), Path::new(key), app.value_of("tls-client-ca").map(Path::new)).map(Some),
+        _ => Ok(None),
+    }
+}
+
+/// Client certificates are required when the client CAs are given
+#[cfg(feature = "tls")]
+fn server_tls_config(cert: &Path, key: &Path, client_ca: Option<&Path>) -> Result<Arc<rustls::ServerConfig>> {
+    let tls_error = |err: &dyn std::fmt::Display| ProxyError::Tls(err.to_string());
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|err| tls_error(&err))?;
+    let builder = match client_ca {
+        Some(client_ca) => {
+            let mut roots = rustls::RootCertStore::empty();
+            for certificate in tls::load_certificates(client_ca)? {
+                roots.add(certificate).map_err(|err| tls_error(&err))?;
+            }
+            let verifier = rustls::server::WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .map_err(|err| tls_error(&err))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder
+        .with_single_cert(tls::load_certificates(cert)?, tls::load_private_key(key)?)
+        .map_err(|err| tls_error(&err))?;
+    Ok(Arc::new(config))
+}
+
+/// The TLS handshake itself happens on the first read
+#[cfg(feature = "tls")]
+fn accept_tls(stream: TcpStream, config: Arc<rustls::ServerConfig>) -> Result<rustls::StreamOwned<rustls::ServerConnection, TcpStream>> {
+    let connection = rustls::ServerConnection::new(config)
+        .map_err(|err| ProxyError::Tls(err.to_string()))?;
+    Ok(rustls::StreamOwned::new(connection, stream))
+}
+
+fn handle_connection<S: Read + Write>(stream: S, settings: &Settings) -> Result<()> {
+    let mut stream = FrameReader::new(stream);
+    let handshake = match stream.read_message(MAX_CONTROL_FRAME_SIZE)? {
+        Message::Connect if settings.auth.is_none() => {
+            Message::Accept.send(stream.get_mut())?;
+            Handshake::legacy()
+        }
+        Message::ConnectWith(offer) if settings.auth.is_none() || offer.supports(Capability::Auth) => {
+            let chosen = offer.choose(&supported_capabilities(settings));
+            Message::AcceptWith(chosen.clone()).send(stream.get_mut())?;
+            chosen
+        }
+        Message::Connect | Message::ConnectWith(_) => {
+            let message = "Authentication required".to_owned();
+            Message::Error { code: 401, message: message.clone() }.send(stream.get_mut())?;
+            return Err(ProxyError::AuthFailed(message));
+        }
+        got => return Err(ProxyError::HandshakeRejected { got }),
+    };
+    if let Some((credentials, mode)) = &settings.auth {
+        authenticate(&mut stream, credentials, *mode)?;
+    }
+    let send_metadata =
+        if handshake.is_legacy() { settings.send_metadata } else { handshake.supports(Capability::Metadata) };
+    let source = &settings.source;
+
+    loop {
+        match stream.read_message(MAX_REQUEST_SIZE)? {
+            Message::Bye => return Message::Bye.send(stream.get_mut()),
+            Message::Ping if handshake.supports(Capability::Ping) => Message::Pong.send(stream.get_mut())?,
+            Message::Get { url } => {
+                let head = RequestHead { method: "GET".to_owned(), url, headers: Vec::new() };
+                respond(&mut stream, &head, Vec::new(), source, &handshake, send_metadata)?;
+            }
+            Message::Request(head) => {
+                let body = stream.load_message(MAX_UPLOAD_SIZE)?;
+                respond(&mut stream, &head, body, source, &handshake, send_metadata)?;
+            }
+            message => {
+                eprintln!("Unexpected {}, closing the connection", message);
+                let message = format!("Unexpected {}", message);
+                return Message::Error { code: 400, message }.send(stream.get_mut());
+            }
+        }
+    }
+}
+
+fn respond<S: Read + Write>(
+    stream: &mut FrameReader<S>,
+    head: &RequestHead,
+    body: Vec<u8>,
+    source: &Source,
+    handshake: &Handshake,
+    send_metadata: bool,
+) -> Result<()> {
+    println!("Serving {} {}", head.method, head.url);
+    let chunked = handshake.supports(Capability::Chunked);
+    // Unless it's chunked the whole body is needed for the frame header,
+    // it's read before anything is sent, so failures can still be reported
+    let served = serve(head, body, source).and_then(|(metadata, mut body)| {
+        let mut data = Vec::new();
+        if !chunked {
+            body.read_to_end(&mut data)?;
+        }
+        Ok((metadata, body, data))
+    });
+    let (metadata, mut body, data) = match served {
+        Ok(served) => served,
+        // Without error frames the client can only tell something went wrong by the closed connection
+        Err(err) if !handshake.supports(Capability::Errors) => return Err(err),
+        Err(err) => {
+            eprintln!("Failed serving {}: {}", head.url, err);
+            let message = match &err {
+                ProxyError::Remote { message, .. } => message.clone(),
+                err => err.to_string(),
+            };
+            return Message::Error { code: error_code(&err, source), message }.send(stream.get_mut());
+        }
+    };
+    if send_metadata {
+        Message::Metadata(metadata).send(stream.get_mut())?;
+    }
+    if chunked {
+        // A failure in the middle can't be reported anymore, the connection is just closed
+        send_chunks(&mut body, stream.get_mut())?;
+        return Ok(());
+    }
+    Message::Data(data).send(stream.get_mut())
+}
+
+/// Authentication is only chosen when it's required
+fn supported_capabilities(settings: &Settings) -> Vec<Capability> {
+    let mut capabilities = Handshake::offer().capabilities;
+    if settings.auth.is_some() {
+        capabilities.push(Capability::Auth);
+    }
+    capabilities
+}
+
+/// A rejection is reported with a 401 error frame before closing the connection
+fn authenticate<S: Read + Write>(stream: &mut FrameReader<S>, credentials: &Credentials, mode: AuthMode) -> Result<()> {
+    let challenge = match mode {
+        AuthMode::Token => AuthChallenge::Token,
+        AuthMode::Hmac => {
+            let mut nonce = vec![0; 32];
+            getrandom::getrandom(&mut nonce).map_err(|err| ProxyError::Io(io::Error::other(err.to_string())))?;
+            AuthChallenge::Hmac { nonce }
+        }
+    };
+    Message::Challenge(challenge.clone()).send(stream.get_mut())?;
+    match stream.read_message(MAX_CONTROL_FRAME_SIZE)? {
+        Message::Auth(response) if credentials.verify(&challenge, &response) =>
+            Message::Authenticated.send(stream.get_mut()),
+        _ => {
+            let message = "Invalid credentials".to_owned();
+            Message::Error { code: 401, message: message.clone() }.send(stream.get_mut())?;
+            Err(ProxyError::AuthFailed(message))
+        }
+    }
+}
+
+/// HTTP-like status codes, so the client side can tell the kinds of failures apart
+fn error_code(err: &ProxyError, source: &Source) -> u32 {
+    match (err, source) {
+        (ProxyError::Remote { code, .. }, _) => *code,
+        (ProxyError::Io(err), Source::Directory(_)) => match err.kind() {
+            ErrorKind::NotFound => 404,
+            ErrorKind::PermissionDenied => 403,
+            _ => 500,
+        },
+        (_, Source::Upstream(_)) => 502,
+        _ => 500,
+    }
+}
+
+/// The body is streamed, from the file or from the upstream connection
+fn serve(head: &RequestHead, body: Vec<u8>, source: &Source) -> Result<(Metadata, Box<dyn Read>)> {
+    match source {
+        Source::Directory(root) => {
+            if head.method != "GET" && head.method != "HEAD" {
+                let message = format!("{} isn't supported when serving a directory", head.method);
+                return Err(ProxyError::Remote { code: 405, message });
+            }
+            let file = File::open(local_path(root, &head.url)?)?;
+            let headers = vec![("Content-Length".to_owned(), file.metadata()?.len().to_string())];
+            let body: Box<dyn Read> = if head.method == "HEAD" { Box::new(io::empty()) } else { Box::new(file) };
+            Ok((Metadata { status: 200, headers }, body))
+        }
+        Source::Upstream(address) => http_request(*address, head, &body),
+    }
+}
+
+/// Maps the URL path into the root, refusing anything that could escape it
+fn local_path(root: &Path, url: &str) -> Result<PathBuf> {
+    let path = url_path(url);
+    let path = path.split(['?', '#']).next().unwrap_or_default();
+    let mut local = root.to_path_buf();
+    for component in Path::new(path.trim_start_matches('/')).components() {
+        match component {
+            Component::Normal(part) => local.push(part),
+            Component::CurDir => {}
+            _ => return Err(ProxyError::Io(std::io::Error::new(
+                ErrorKind::PermissionDenied,
+                format!("Refusing to serve {:?}", path),
+            ))),
+        }
+    }
+    if local.is_dir() {
+        local.push("index.html");
+    }
+    Ok(local)
+}
+
+/// Strips the scheme and the host, so "http://host/a/b" becomes "/a/b"
+fn url_path(url: &str) -> &str {
+    match url.find("://") {
+        Some(scheme_end) => {
+            let rest = &url[scheme_end + 3..];
+            rest.find('/').map(|index| &rest[index..]).unwrap_or("/")
+        }
+        None => url,
+    }
+}
+
+fn url_host(url: &str) -> &str {
+    let rest = url.find("://").map(|index| &url[index + 3..]).unwrap_or("");
+    rest.split('/').next().unwrap_or_default()
+}
+
+/// Minimal HTTP/1.0 client, returns the status and headers, leaving the body to be streamed
+fn http_request(upstream: SocketAddr, head: &RequestHead, body: &[u8]) -> Result<(Metadata, Box<dyn Read>)> {
+    let mut stream = TcpStream::connect(upstream)?;
+    let mut request = format!("{} {} HTTP/1.0\r\n", head.method, url_path(&head.url));
+    let has_header = |name: &str| head.headers.iter().any(|(header, _)| header.eq_ignore_ascii_case(name));
+    if !has_header("Host") {
+        let host = url_host(&head.url);
+        let host = if host.is_empty() { upstream.to_string() } else { host.to_owned() };
+        request.push_str(&format!("Host: {}\r\n", host));
+    }
+    for (name, value) in &head.headers {
+        if !name.eq_ignore_ascii_case("Content-Length") && !name.eq_ignore_ascii_case("Connection") {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+    }
+    if !body.is_empty() || head.method == "POST" || head.method == "PUT" {
+        request.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+    request.push_str("Connection: close\r\n\r\n");
+    stream.write_all(request.as_bytes())?;
+    stream.write_all(body)?;
+    let mut response = BufReader::new(stream);
+    let mut response_head = Vec::new();
+    while !response_head.ends_with(b"\r\n\r\n") {
+        if response.read_until(b'\n', &mut response_head)? == 0 {
+            break;
+        }
+    }
+    Ok((parse_http_head(&response_head)?, Box::new(response)))
+}
+
+/// "HTTP/1.0 200 OK" followed by the header lines
+fn parse_http_head(head: &[u8]) -> Result<Metadata> {
+    let invalid = || ProxyError::Io(std::io::Error::new(ErrorKind::InvalidData, "Invalid HTTP response from upstream"));
+    let head = String::from_utf8_lossy(head);
+    let mut lines = head.split("\r\n").filter(|line| !line.is_empty());
+    let status = lines.next()
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(invalid)?;
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.to_owned(), value.trim().to_owned()))
+        .collect();
+    Ok(Metadata { status, headers })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use rust_proxy_tcp_client::protocol::CHUNK_SIZE;
+    #[cfg(feature = "tls")]
+    use sha2::{Digest, Sha256};
+    #[cfg(feature = "async")]
+    use rust_proxy_tcp_client::AsyncProxyClient;
+    #[cfg(feature = "tls")]
+    use rust_proxy_tcp_client::TlsConfig;
+    use rust_proxy_tcp_client::{ClientConfig, ProxyClient};
+    use super::*;
+
+    /// A directory with a small and a multi-chunk file
+    fn test_root(name: &str) -> PathBuf {
+        let root = env::temp_dir().join(format!("proxy_server_{}_{}", name, process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("a.txt"), "hello\n").unwrap();
+        fs::write(root.join("big.bin"), big_body()).unwrap();
+        root
+    }
+
+    fn big_body() -> Vec<u8> {
+        (0..CHUNK_SIZE * 3 + 7).map(|index| index as u8).collect()
+    }
+
+    fn settings(root: PathBuf) -> Settings {
+        Settings { source: Source::Directory(root), send_metadata: false, auth: None, idle_timeout: None }
+    }
+
+    /// Handles the connections on a loopback port until the test ends
+    fn serve_tcp(settings: Settings) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let settings = settings.clone();
+                thread::spawn(move || handle_connection(stream.unwrap(), &settings));
+            }
+        });
+        address
+    }
+
+    #[test]
+    fn serves_many_urls_over_one_session() {
+        let root = test_root("session");
+        let mut client = ProxyClient::connect(serve_tcp(settings(root.clone()))).unwrap();
+        assert_eq!(client.handshake(), &Handshake::offer());
+        let mut body = Vec::new();
+        let response = client.fetch_to("http://host/a.txt", &mut body).unwrap();
+        assert_eq!(body, b"hello\n");
+        assert_eq!(response.metadata, Some(Metadata {
+            status: 200,
+            headers: vec![("Content-Length".to_owned(), "6".to_owned())],
+        }));
+        assert!(matches!(client.fetch("http://host/missing"), Err(ProxyError::Remote { code: 404, .. })));
+        // Sent in chunks, and the session goes on after the error
+        assert_eq!(client.fetch("http://host/big.bin").unwrap(), big_body());
+        client.ping().unwrap();
+        assert_eq!(client.fetch("http://host/a.txt").unwrap(), b"hello\n");
+        client.bye().unwrap();
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn speaks_the_legacy_protocol() {
+        let root = test_root("legacy");
+        let legacy = ClientConfig { legacy_handshake: true, ..ClientConfig::default() };
+        let mut client = ProxyClient::connect_with_config(serve_tcp(settings(root.clone())), legacy.clone()).unwrap();
+        assert_eq!(client.handshake(), &Handshake::legacy());
+        let mut body = Vec::new();
+        assert_eq!(client.fetch_to("http://host/big.bin", &mut body).unwrap().metadata, None);
+        assert_eq!(body, big_body());
+        client.bye().unwrap();
+
+        let with_metadata = Settings { send_metadata: true, ..settings(root.clone()) };
+        let mut client = ProxyClient::connect_with_config(serve_tcp(with_metadata), legacy).unwrap();
+        let response = client.fetch_to("http://host/a.txt", &mut Vec::new()).unwrap();
+        assert_eq!(response.metadata.map(|metadata| metadata.status), Some(200));
+        assert!(matches!(client.fetch("http://host/missing"), Err(ProxyError::Remote { code: 404, .. })));
+        client.bye().unwrap();
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn serves_the_async_client() {
+        let root = test_root("async");
+        let mut client = AsyncProxyClient::connect(serve_tcp(settings(root.clone()))).await.unwrap();
+        assert_eq!(client.handshake(), &Handshake::offer());
+        let (metadata, body) = client.fetch_response("http://host/a.txt").await.unwrap();
+        assert_eq!(body, b"hello\n");
+        assert_eq!(metadata, Some(Metadata {
+            status: 200,
+            headers: vec![("Content-Length".to_owned(), "6".to_owned())],
+        }));
+        assert!(matches!(client.fetch("http://host/missing").await, Err(ProxyError::Remote { code: 404, .. })));
+        assert_eq!(client.fetch("http://host/big.bin").await.unwrap(), big_body());
+        client.bye().await.unwrap();
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[cfg(feature = "tls")]
+    fn test_certificate(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/tls").join(name)
+    }
+
+    /// Terminates TLS with the test server certificate on a loopback port until the test ends
+    #[cfg(feature = "tls")]
+    fn serve_tls(settings: Settings, client_ca: Option<&Path>) -> String {
+        let config = server_tls_config(&test_certificate("server.pem"), &test_certificate("server.key"), client_ca).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let (settings, config) = (settings.clone(), config.clone());
+                thread::spawn(move || accept_tls(stream.unwrap(), config).and_then(|stream| handle_connection(stream, &settings)));
+            }
+        });
+        address
+    }
+
+    #[cfg(feature = "tls")]
+    fn connect_tls(address: &str, tls: TlsConfig) -> Result<ProxyClient> {
+        ProxyClient::connect_with_config(address, ClientConfig { tls: Some(tls), ..ClientConfig::default() })
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn serves_tls_to_clients_trusting_the_ca() {
+        let root = test_root("tls_ca");
+        let address = serve_tls(settings(root.clone()), None);
+        let trusted = TlsConfig { ca_file: Some(test_certificate("ca.pem")), ..TlsConfig::default() };
+        let mut client = connect_tls(&address, trusted.clone()).unwrap();
+        assert_eq!(client.fetch("http://host/a.txt").unwrap(), b"hello\n");
+        client.bye().unwrap();
+        let wrong_pin = TlsConfig { pinned_sha256: vec![[0; 32]], ..trusted };
+        assert!(matches!(connect_tls(&address, wrong_pin), Err(ProxyError::Tls(_))));
+        // Not signed by any of the built-in roots
+        assert!(matches!(connect_tls(&address, TlsConfig::default()), Err(ProxyError::Tls(_))));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn trusts_a_pinned_certificate_without_a_ca() {
+        let root = test_root("tls_pin");
+        let address = serve_tls(settings(root.clone()), None);
+        let certificate = tls::load_certificates(&test_certificate("server.pem")).unwrap().remove(0);
+        let pinned = TlsConfig { pinned_sha256: vec![Sha256::digest(certificate.as_ref()).into()], ..TlsConfig::default() };
+        let mut client = connect_tls(&address, pinned).unwrap();
+        assert_eq!(client.fetch("http://host/a.txt").unwrap(), b"hello\n");
+        client.bye().unwrap();
+        let wrong_pin = TlsConfig { pinned_sha256: vec![[0; 32]], ..TlsConfig::default() };
+        assert!(matches!(connect_tls(&address, wrong_pin), Err(ProxyError::Tls(_))));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn requires_client_certificates_signed_by_the_client_ca() {
+        let root = test_root("tls_client");
+        let address = serve_tls(settings(root.clone()), Some(&test_certificate("ca.pem")));
+        let trusted = TlsConfig { ca_file: Some(test_certificate("ca.pem")), ..TlsConfig::default() };
+        let with_certificate = TlsConfig {
+            client_cert: Some(test_certificate("client.pem")),
+            client_key: Some(test_certificate("client.key")),
+            ..trusted.clone()
+        };
+        let mut client = connect_tls(&address, with_certificate).unwrap();
+        assert_eq!(client.fetch("http://host/a.txt").unwrap(), b"hello\n");
+        client.bye().unwrap();
+        assert!(connect_tls(&address, trusted).is_err());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn serves_unix_sockets() {
+        let root = test_root("unix");
+        let path = root.join("proxy.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        let settings = settings(root.clone());
+        let server = thread::spawn(move || handle_connection(listener.accept().unwrap().0, &settings));
+        let mut client = ProxyClient::connect(format!("{}{}", UNIX_SOCKET_PREFIX, path.display())).unwrap();
+        assert_eq!(client.fetch("http://host/a.txt").unwrap(), b"hello\n");
+        client.bye().unwrap();
+        server.join().unwrap().unwrap();
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,717 @@
+use std::cmp::min;
+use std::fmt::Display;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::auth::{AuthChallenge, Credentials};
+use crate::config::ClientConfig;
+use crate::connect::{connect_any, resolve, set_keepalive};
+#[cfg(unix)]
+use crate::connect::UNIX_SOCKET_PREFIX;
+use crate::error::{Phase, ProxyError, Result};
+use crate::message::{Capability, Handshake, Message, Metadata};
+pub use crate::protocol::Response;
+use crate::protocol::{send_bytes, FrameReader, MAX_CONTROL_FRAME_SIZE};
+use crate::request::Request;
+#[cfg(feature = "tls")]
+use crate::tls;
+use crate::transport::Transport;
+
+/// A session with the proxy server.
+/// Owns the connection, so the Connect/Accept handshake is done once
+/// and any number of URLs can be fetched before saying bye.
+/// With a heartbeat interval a background thread pings the proxy while the session is idle.
+pub struct ProxyClient {
+    proxy_server_address: String,
+    /// Shared with the heartbeat thread
+    session: Arc<Mutex<Session>>,
+    handshake: Handshake,
+    config: ClientConfig,
+    deadline: Option<Instant>,
+    /// Dropping it stops the heartbeat thread
+    _stop_heartbeats: Option<Sender<()>>,
+}
+
+/// The connection and what the heartbeat thread needs to know about it
+struct Session {
+    connection: Connection,
+    /// When the proxy was last heard from
+    last_activity: Instant,
+    /// Whether the proxy agreed to heartbeats
+    heartbeats: bool,
+    /// The proxy didn't answer a heartbeat, the client reconnects before it's used next
+    dead: bool,
+}
+
+impl ProxyClient {
+    /// Opens the connection and performs the Connect/Accept handshake.
+    /// The address is either a socket address or a "host:port" pair to resolve.
+    pub fn connect<A: Display>(proxy_server_address: A) -> Result<ProxyClient> {
+        ProxyClient::connect_with_config(proxy_server_address, ClientConfig::default())
+    }
+
+    /// Same as `connect`, with the timeouts and the retry policy taken from the config
+    pub fn connect_with_config<A: Display>(proxy_server_address: A, config: ClientConfig) -> Result<ProxyClient> {
+        let proxy_server_address = proxy_server_address.to_string();
+        // A deadline too far away to represent is as good as none
+        let deadline = config.deadline.and_then(|deadline| Instant::now().checked_add(deadline));
+        let mut attempt = 1;
+        loop {
+            match establish(&proxy_server_address, &config, deadline) {
+                Ok((connection, handshake)) => {
+                    let session = Arc::new(Mutex::new(Session::new(connection, &handshake)));
+                    let stop_heartbeats = config.heartbeat_interval.map(|interval| {
+                        let (stop, stopped) = mpsc::channel();
+                        let (session, config) = (Arc::clone(&session), config.clone());
+                        thread::spawn(move || send_heartbeats(&session, &config, interval, &stopped));
+                        stop
+                    });
+                    return Ok(ProxyClient {
+                        proxy_server_address,
+                        session,
+                        handshake,
+                        config,
+                        deadline,
+                        _stop_heartbeats: stop_heartbeats,
+                    });
+                }
+                Err(err) if config.retry.should_retry(&err, attempt) => wait_before_retry(&config, deadline, attempt, err)?,
+                Err(err) => return Err(err),
+            }
+            attempt += 1;
+        }
+    }
+
+    /// The protocol version and the capabilities agreed on with the proxy
+    pub fn handshake(&self) -> &Handshake {
+        &self.handshake
+    }
+
+    /// Asks the proxy for the given URL and returns the response body
+    pub fn fetch(&mut self, url: &str) -> Result<Vec<u8>> {
+        let mut body = Vec::new();
+        self.fetch_to(url, &mut body)?;
+        Ok(body)
+    }
+
+    /// Asks the proxy for the given URL and streams the response body into `out`.
+    ///
+    /// Failures are retried according to the retry policy, but only until
+    /// the first byte of the body reaches `out`, as it can't be taken back.
+    pub fn fetch_to<W: Write>(&mut self, url: &str, out: &mut W) -> Result<Response> {
+        self.fetch_to_with(url, out, |_, _| Ok(()))
+    }
+
+    /// Same as `fetch_to`, calling `on_metadata` when the metadata arrives, before the body.
+    /// Anything it writes into the given writer ends up in `out` ahead of the body.
+    pub fn fetch_to_with<W, F>(&mut self, url: &str, out: &mut W, on_metadata: F) -> Result<Response>
+    where
+        W: Write,
+        F: FnMut(&Metadata, &mut dyn Write) -> io::Result<()>,
+    {
+        self.send_to_with(&Request::get(url), out, on_metadata)
+    }
+
+    /// Sends any request, with its method, headers and body, and streams the response into `out`
+    /// like `fetch_to_with`. Only the idempotent methods are retried.
+    pub fn send_to_with<W, F>(&mut self, request: &Request, out: &mut W, mut on_metadata: F) -> Result<Response>
+    where
+        W: Write,
+        F: FnMut(&Metadata, &mut dyn Write) -> io::Result<()>,
+    {
+        self.keep_alive()?;
+        let mut attempt = 1;
+        loop {
+            let mut counter = CountingWriter { inner: &mut *out, count: 0 };
+            let result = if attempt == 1 { Ok(()) } else { self.reconnect() }
+                .and_then(|()| self.exchange(request, &mut counter, &mut on_metadata));
+            match result {
+                Ok(response) => return Ok(response),
+                Err(err) if counter.count == 0
+                    && request.is_idempotent()
+                    && self.config.retry.should_retry(&err, attempt) => {
+                    wait_before_retry(&self.config, self.deadline, attempt, err)?;
+                }
+                Err(err) => return Err(err),
+            }
+            attempt += 1;
+        }
+    }
+
+    fn exchange<W, F>(&self, request: &Request, out: &mut W, on_metadata: &mut F) -> Result<Response>
+    where
+        W: Write,
+        F: FnMut(&Metadata, &mut dyn Write) -> io::Result<()>,
+    {
+        let mut session = self.lock();
+        self.config.report(format_args!("Sending {} {}", request.method, request.url));
+        request.send(session.connection.get_mut(), &self.handshake)
+            .map_err(|err| err.in_phase(Phase::Request))?;
+        self.config.report(format_args!("Waiting for the response"));
+        let max_length = self.config.max_response_size.unwrap_or(u64::MAX);
+        let response = session.connection.copy_response(out, max_length, &self.handshake, on_metadata)
+            .map_err(|err| err.in_phase(Phase::Response))?;
+        session.last_activity = Instant::now();
+        Ok(response)
+    }
+
+    /// Sends a PING and waits for the PONG, returning the round trip time.
+    /// Waits at most the dead peer timeout, or the read timeout when it isn't set.
+    pub fn ping(&mut self) -> Result<Duration> {
+        if !self.handshake.supports(Capability::Ping) {
+            return Err(ProxyError::InvalidRequest("The proxy doesn't support heartbeats".to_owned()));
+        }
+        self.lock().ping(&self.config)
+    }
+
+    /// Sends one frame with the payload as it is, for exploring how the proxy reacts.
+    /// Nothing checks that it fits the protocol.
+    pub fn send_frame(&mut self, payload: &[u8]) -> Result<()> {
+        send_bytes(payload, self.lock().connection.get_mut())
+    }
+
+    /// Reads the next frame whatever it holds, waiting at most `timeout`,
+    /// or the read timeout when it's `None`. Limited by the max response size.
+    pub fn read_frame(&mut self, timeout: Option<Duration>) -> Result<Vec<u8>> {
+        let max_length = self.config.max_response_size.unwrap_or(u64::MAX);
+        let mut session = self.lock();
+        if timeout.is_some() {
+            session.connection.get_mut().set_read_timeout(timeout)?;
+        }
+        let result = session.connection.load_message(max_length);
+        if timeout.is_some() {
+            session.connection.get_mut().set_read_timeout(self.config.read_timeout)?;
+        }
+        let frame = result?;
+        session.last_activity = Instant::now();
+        Ok(frame)
+    }
+
+    /// Reconnects when the heartbeat thread found the proxy gone, and pings it first
+    /// when the session was idle longer than the heartbeat interval. Called before every request.
+    pub fn keep_alive(&mut self) -> Result<()> {
+        let dead = {
+            let mut session = self.lock();
+            let idle = session.last_activity.elapsed();
+            let due = self.config.heartbeat_interval.is_some_and(|interval| idle >= interval);
+            if due && session.heartbeats && !session.dead {
+                session.heartbeat(&self.config);
+            }
+            session.dead
+        };
+        if dead {
+            self.reconnect()?;
+        }
+        Ok(())
+    }
+    /// Ends the session, consuming the client
+    pub fn bye(mut self) -> Result<()> {
+        self.say_bye().map_err(|err| err.in_phase(Phase::Bye))
+    }
+
+    fn say_bye(&mut self) -> Result<()> {
+        let mut session = self.lock();
+        self.config.report(format_args!("Sending bye message"));
+        Message::Bye.send(session.connection.get_mut())?;
+        self.config.report(format_args!("Waiting for bye response"));
+        let response = session.connection.read_message(MAX_CONTROL_FRAME_SIZE)?;
+        if response != Message::Bye {
+            return Err(ProxyError::ByeMismatch { got: response });
+        }
+        Ok(())
+    }
+
+    /// Drops the current connection and starts a new session
+    fn reconnect(&mut self) -> Result<()> {
+        self.config.report(format_args!("Reconnecting to {}", self.proxy_server_address));
+        let (connection, handshake) = establish(&self.proxy_server_address, &self.config, self.deadline)?;
+        *self.lock() = Session::new(connection, &handshake);
+        self.handshake = handshake;
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Session> {
+        self.session.lock().expect("The heartbeat thread panicked")
+    }
+}
+
+type Connection = FrameReader<DeadlineStream>;
+
+impl Session {
+    fn new(connection: Connection, handshake: &Handshake) -> Session {
+        Session {
+            connection,
+            last_activity: Instant::now(),
+            heartbeats: handshake.supports(Capability::Ping),
+            dead: false,
+        }
+    }
+
+    fn ping(&mut self, config: &ClientConfig) -> Result<Duration> {
+        let started = Instant::now();
+        let timeout = config.dead_peer_timeout.or(config.read_timeout);
+        self.connection.get_mut().set_read_timeout(timeout)?;
+        let result = self.exchange_ping();
+        self.connection.get_mut().set_read_timeout(config.read_timeout)?;
+        result.map_err(|err| err.in_phase(Phase::Heartbeat))?;
+        self.last_activity = Instant::now();
+        Ok(started.elapsed())
+    }
+
+    fn exchange_ping(&mut self) -> Result<()> {
+        Message::Ping.send(self.connection.get_mut())?;
+        match self.connection.read_message(MAX_CONTROL_FRAME_SIZE)? {
+            Message::Pong => Ok(()),
+            got => Err(ProxyError::UnexpectedMessage { got }),
+        }
+    }
+
+    /// Pings the proxy, marking it dead when it doesn't answer
+    fn heartbeat(&mut self, config: &ClientConfig) {
+        if let Err(err) = self.ping(config) {
+            config.report(format_args!("The proxy didn't answer the heartbeat: {}", err));
+            self.dead = true;
+        }
+    }
+}
+
+/// Runs on its own thread until the client is dropped, pinging the proxy
+/// whenever the session was idle for the interval
+fn send_heartbeats(session: &Mutex<Session>, config: &ClientConfig, interval: Duration, stopped: &Receiver<()>) {
+    let mut wait = interval;
+    while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(wait) {
+        let mut session = session.lock().expect("The client panicked");
+        let idle = session.last_activity.elapsed();
+        wait = interval;
+        if !session.heartbeats || session.dead {
+            continue;
+        }
+        if idle < interval {
+            wait = interval - idle;
+            continue;
+        }
+        session.heartbeat(config);
+    }
+}
+
+/// Reports the failed attempt and sleeps before the next one.
+/// Gives up with the error when the next attempt couldn't start before the deadline.
+fn wait_before_retry(config: &ClientConfig, deadline: Option<Instant>, attempt: u32, err: ProxyError) -> Result<()> {
+    let delay = match config.retry.delay_before(attempt, deadline) {
+        Some(delay) => delay,
+        None => return Err(err),
+    };
+    config.report_retry(format_args!("Attempt {} of {} failed: {}, retrying in {:?}", attempt, config.retry.max_attempts, err, delay));
+    thread::sleep(delay);
+    Ok(())
+}
+
+/// Connects and performs the Connect/Accept handshake
+fn establish(proxy_server_address: &str, config: &ClientConfig, deadline: Option<Instant>) -> Result<(Connection, Handshake)> {
+    let socket = open_transport(proxy_server_address, config, deadline)
+        .map_err(|err| err.in_phase(Phase::Connect))?;
+    socket.set_read_timeout(config.read_timeout)?;
+    socket.set_write_timeout(config.write_timeout)?;
+    let mut connection = FrameReader::new(DeadlineStream {
+        socket,
+        read_timeout: config.read_timeout,
+        write_timeout: config.write_timeout,
+        deadline,
+    });
+    let handshake = handshake(&mut connection, config)
+        .map_err(|err| err.in_phase(Phase::Handshake))?;
+    Ok((connection, handshake))
+}
+
+/// "unix:/path" connects to a Unix domain socket, anything else is resolved
+/// as a TCP address. It's resolved every time, so reconnects follow DNS changes.
+fn open_transport(proxy_server_address: &str, config: &ClientConfig, deadline: Option<Instant>) -> Result<Transport> {
+    #[cfg(unix)]
+    if let Some(path) = proxy_server_address.strip_prefix(UNIX_SOCKET_PREFIX) {
+        #[cfg(feature = "tls")]
+        if config.tls.is_some() {
+            return Err(ProxyError::Tls("TLS isn't supported over Unix domain sockets".to_owned()));
+        }
+        return Ok(Transport::Unix(UnixStream::connect(path)?));
+    }
+    let addresses = resolve(proxy_server_address)?;
+    let connect_timeout = capped_timeout(config.connect_timeout, deadline)?;
+    config.report(format_args!("Connecting to {} at {:?}", proxy_server_address, addresses));
+    let socket = connect_any(proxy_server_address, &addresses, connect_timeout)?;
+    if let Some(idle) = config.keepalive {
+        set_keepalive(&socket, idle)?;
+    }
+    // Set before the TLS handshake, so a proxy that never answers it can't block past the timeouts
+    socket.set_read_timeout(capped_timeout(config.read_timeout, deadline)?)?;
+    socket.set_write_timeout(capped_timeout(config.write_timeout, deadline)?)?;
+    secure(socket, config, proxy_server_address)
+}
+
+#[cfg(feature = "tls")]
+fn secure(socket: TcpStream, config: &ClientConfig, proxy_server_address: &str) -> Result<Transport> {
+    match &config.tls {
+        Some(tls_config) => Ok(Transport::Tls(Box::new(tls::wrap(socket, tls_config, proxy_server_address)?))),
+        None => Ok(Transport::Tcp(socket)),
+    }
+}
+
+#[cfg(not(feature = "tls"))]
+fn secure(socket: TcpStream, _config: &ClientConfig, _proxy_server_address: &str) -> Result<Transport> {
+    Ok(Transport::Tcp(socket))
+}
+
+/// Offers the newest version, a plain Accept means the server only speaks version 1
+fn handshake(connection: &mut Connection, config: &ClientConfig) -> Result<Handshake> {
+    config.report(format_args!("Sending connect"));
+    let mut offer = Handshake::offer();
+    if config.credentials.is_some() {
+        offer.capabilities.push(Capability::Auth);
+    }
+    if config.legacy_handshake {
+        Message::Connect.send(connection.get_mut())?;
+    } else {
+        Message::ConnectWith(offer.clone()).send(connection.get_mut())?;
+    }
+    config.report(format_args!("Waiting for acceptance"));
+    let handshake = match connection.read_message(MAX_CONTROL_FRAME_SIZE)? {
+        Message::Accept => Handshake::legacy(),
+        // Never more than what was offered, whatever the server says
+        Message::AcceptWith(chosen) if !config.legacy_handshake => chosen.choose(&offer.capabilities),
+        Message::Error { code: 401, message } => return Err(ProxyError::AuthFailed(message)),
+        response => return Err(ProxyError::HandshakeRejected { got: response }),
+    };
+    if handshake.supports(Capability::Auth) {
+        let credentials = config.credentials.as_ref().expect("Only offered with credentials");
+        let token_allowed = config.allow_plaintext_token || connection.get_mut().socket.is_private();
+        config.report(format_args!("Waiting for the authentication challenge"));
+        authenticate(connection, credentials, token_allowed)?;
+    }
+    Ok(handshake)
+}
+
+/// The plain token is only sent when `token_allowed`, otherwise anyone answering
+/// the connection could ask for it instead of the HMAC and read it
+fn authenticate(connection: &mut Connection, credentials: &Credentials, token_allowed: bool) -> Result<()> {
+    let challenge = match connection.read_message(MAX_CONTROL_FRAME_SIZE)? {
+        Message::Challenge(AuthChallenge::Token) if !token_allowed => return Err(ProxyError::AuthFailed(
+            "The proxy asked for the plain token over an unencrypted connection, \
+             use TLS or allow sending it in plain text".to_owned(),
+        )),
+        Message::Challenge(challenge) => challenge,
+        response => return Err(ProxyError::HandshakeRejected { got: response }),
+    };
+    Message::Auth(credentials.respond(&challenge)).send(connection.get_mut())?;
+    match connection.read_message(MAX_CONTROL_FRAME_SIZE)? {
+        Message::Authenticated => Ok(()),
+        Message::Error { message, .. } => Err(ProxyError::AuthFailed(message)),
+        response => Err(ProxyError::HandshakeRejected { got: response }),
+    }
+}
+
+/// Remembers whether anything was written, to know if a retry is still possible
+struct CountingWriter<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = self.inner.write(buf)?;
+        self.count += count as u64;
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Shrinks the socket timeouts before every read and write,
+/// so no single call can block past the session deadline
+struct DeadlineStream {
+    socket: Transport,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    deadline: Option<Instant>,
+}
+
+impl DeadlineStream {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.read_timeout = timeout;
+        self.socket.set_read_timeout(capped_timeout(timeout, self.deadline)?)
+    }
+}
+
+impl Read for DeadlineStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.deadline.is_some() {
+            self.socket.set_read_timeout(capped_timeout(self.read_timeout, self.deadline)?)?;
+        }
+        self.socket.read(buf)
+    }
+}
+
+impl Write for DeadlineStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.deadline.is_some() {
+            self.socket.set_write_timeout(capped_timeout(self.write_timeout, self.deadline)?)?;
+        }
+        self.socket.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.socket.flush()
+    }
+}
+
+/// The smaller of the timeout and the time left until the deadline
+fn capped_timeout(timeout: Option<Duration>, deadline: Option<Instant>) -> io::Result<Option<Duration>> {
+    let deadline = match deadline {
+        Some(deadline) => deadline,
+        None => return Ok(timeout),
+    };
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    if remaining.is_zero() {
+        return Err(io::Error::new(io::ErrorKind::TimedOut, "The session deadline has passed"));
+    }
+    Ok(Some(timeout.map_or(remaining, |timeout| min(timeout, remaining))))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use crate::config::ProgressHook;
+    use crate::request::RequestBody;
+    use crate::retry::{ErrorClass, RetryPolicy};
+    use super::*;
+
+    /// Accepts a connection and answers the handshake with the given capabilities
+    fn accept_session(listener: &TcpListener, capabilities: &[Capability]) -> FrameReader<TcpStream> {
+        let mut stream = FrameReader::new(listener.accept().unwrap().0);
+        assert!(matches!(stream.read_message(MAX_CONTROL_FRAME_SIZE).unwrap(), Message::ConnectWith(_)));
+        let accept = Handshake { version: 2, capabilities: capabilities.to_vec() };
+        Message::AcceptWith(accept).send(stream.get_mut()).unwrap();
+        stream
+    }
+
+    /// A proxy on a loopback port answering the first handshake,
+    /// `then` takes over the connection and can accept more from the listener
+    fn fake_proxy<F>(capabilities: Vec<Capability>, then: F) -> (String, thread::JoinHandle<()>)
+    where
+        F: FnOnce(&mut FrameReader<TcpStream>, &TcpListener) + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let proxy = thread::spawn(move || {
+            let mut stream = accept_session(&listener, &capabilities);
+            then(&mut stream, &listener);
+        });
+        (address, proxy)
+    }
+
+    fn answer_ping(stream: &mut FrameReader<TcpStream>) {
+        assert_eq!(stream.read_message(MAX_CONTROL_FRAME_SIZE).unwrap(), Message::Ping);
+        Message::Pong.send(stream.get_mut()).unwrap();
+    }
+
+    /// Answers the heartbeats until the bye, returning how many there were
+    fn answer_pings_until_bye(stream: &mut FrameReader<TcpStream>) -> usize {
+        let mut pings = 0;
+        loop {
+            match stream.read_message(MAX_CONTROL_FRAME_SIZE).unwrap() {
+                Message::Ping => {
+                    pings += 1;
+                    Message::Pong.send(stream.get_mut()).unwrap();
+                }
+                Message::Bye => {
+                    Message::Bye.send(stream.get_mut()).unwrap();
+                    return pings;
+                }
+                got => panic!("Unexpected {}", got),
+            }
+        }
+    }
+
+    /// Reads whatever comes without answering until the client hangs up
+    fn ignore_until_hangup(stream: &mut FrameReader<TcpStream>) {
+        while stream.load_message(u64::MAX).is_ok() {}
+    }
+
+    fn heartbeat_config(interval: Duration) -> ClientConfig {
+        ClientConfig {
+            heartbeat_interval: Some(interval),
+            dead_peer_timeout: Some(Duration::from_secs(5)),
+            ..ClientConfig::default()
+        }
+    }
+
+    #[test]
+    fn refuses_to_send_the_plain_token_over_tcp() {
+        let (address, proxy) = fake_proxy(vec![Capability::Auth], |stream, _| {
+            Message::Challenge(AuthChallenge::Token).send(stream.get_mut()).unwrap();
+            // The client hangs up instead of answering
+            assert!(matches!(stream.read_message(MAX_CONTROL_FRAME_SIZE), Err(ProxyError::UnexpectedEof)));
+        });
+        let config = ClientConfig { credentials: Some(Credentials::new("secret")), ..ClientConfig::default() };
+        assert!(matches!(ProxyClient::connect_with_config(address, config), Err(ProxyError::AuthFailed(_))));
+        proxy.join().unwrap();
+    }
+
+    #[test]
+    fn sends_the_plain_token_when_allowed() {
+        let (address, proxy) = fake_proxy(vec![Capability::Auth], |stream, _| {
+            Message::Challenge(AuthChallenge::Token).send(stream.get_mut()).unwrap();
+            let response = stream.read_message(MAX_CONTROL_FRAME_SIZE).unwrap();
+            assert_eq!(response, Message::Auth(Credentials::new("secret").respond(&AuthChallenge::Token)));
+            Message::Authenticated.send(stream.get_mut()).unwrap();
+        });
+        let config = ClientConfig {
+            credentials: Some(Credentials::new("secret")),
+            allow_plaintext_token: true,
+            ..ClientConfig::default()
+        };
+        ProxyClient::connect_with_config(address, config).unwrap();
+        proxy.join().unwrap();
+    }
+
+    #[test]
+    fn ping_waits_for_the_pong() {
+        let (address, proxy) = fake_proxy(vec![Capability::Ping], |stream, _| answer_ping(stream));
+        let mut client = ProxyClient::connect(address).unwrap();
+        client.ping().unwrap();
+        proxy.join().unwrap();
+    }
+
+    #[test]
+    fn pings_the_idle_proxy_in_the_background() {
+        let (sender, pings) = mpsc::channel();
+        let (address, proxy) = fake_proxy(vec![Capability::Ping], move |stream, _| {
+            sender.send(answer_pings_until_bye(stream)).unwrap();
+        });
+        let client = ProxyClient::connect_with_config(address, heartbeat_config(Duration::from_millis(50))).unwrap();
+        thread::sleep(Duration::from_millis(180));
+        client.bye().unwrap();
+        proxy.join().unwrap();
+        let pings = pings.recv().unwrap();
+        assert!((2..=4).contains(&pings), "{} pings", pings);
+    }
+
+    #[test]
+    fn reconnects_when_the_proxy_doesnt_answer_the_heartbeat() {
+        let (address, proxy) = fake_proxy(vec![Capability::Ping], |stream, listener| {
+            assert_eq!(stream.read_message(MAX_CONTROL_FRAME_SIZE).unwrap(), Message::Ping);
+            stream.get_mut().shutdown(std::net::Shutdown::Both).unwrap();
+            answer_pings_until_bye(&mut accept_session(listener, &[Capability::Ping]));
+        });
+        let mut client = ProxyClient::connect_with_config(address, heartbeat_config(Duration::from_millis(20))).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        client.keep_alive().unwrap();
+        client.ping().unwrap();
+        client.bye().unwrap();
+        proxy.join().unwrap();
+    }
+
+    #[test]
+    fn no_heartbeats_without_the_capability() {
+        let (address, proxy) = fake_proxy(Vec::new(), |stream, _| assert_eq!(answer_pings_until_bye(stream), 0));
+        let client = ProxyClient::connect_with_config(address, heartbeat_config(Duration::from_millis(10))).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        client.bye().unwrap();
+        proxy.join().unwrap();
+    }
+
+    #[test]
+    fn times_out_waiting_for_the_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let proxy = thread::spawn(move || {
+            for _ in 0..2 {
+                ignore_until_hangup(&mut FrameReader::new(listener.accept().unwrap().0));
+            }
+        });
+        let read_timeout = ClientConfig { read_timeout: Some(Duration::from_millis(100)), ..ClientConfig::default() };
+        let deadline = ClientConfig { deadline: Some(Duration::from_millis(100)), ..ClientConfig::default() };
+        for config in [read_timeout, deadline] {
+            let started = Instant::now();
+            let result = ProxyClient::connect_with_config(&address, config);
+            assert!(matches!(result, Err(ProxyError::Timeout { phase: Phase::Handshake })), "{:?}", result.map(|_| ()));
+            assert!(started.elapsed() < Duration::from_secs(2));
+        }
+        proxy.join().unwrap();
+    }
+
+    #[test]
+    fn times_out_waiting_for_the_response() {
+        let (address, proxy) = fake_proxy(Vec::new(), |stream, _| {
+            assert!(matches!(stream.read_message(MAX_CONTROL_FRAME_SIZE).unwrap(), Message::Get { .. }));
+            ignore_until_hangup(stream);
+        });
+        let config = ClientConfig { read_timeout: Some(Duration::from_millis(100)), ..ClientConfig::default() };
+        let mut client = ProxyClient::connect_with_config(address, config).unwrap();
+        assert!(matches!(client.fetch("http://host/"), Err(ProxyError::Timeout { phase: Phase::Response })));
+        drop(client);
+        proxy.join().unwrap();
+    }
+
+    #[test]
+    fn times_out_sending_to_a_proxy_that_doesnt_read() {
+        let (address, proxy) = fake_proxy(vec![Capability::Requests], |stream, _| {
+            // Reads nothing until the client gave up
+            thread::sleep(Duration::from_millis(500));
+            ignore_until_hangup(stream);
+        });
+        let config = ClientConfig { write_timeout: Some(Duration::from_millis(100)), ..ClientConfig::default() };
+        let mut client = ProxyClient::connect_with_config(address, config).unwrap();
+        // Far more than the socket buffers hold
+        let request = Request::new("POST", "http://host/").body(RequestBody::Bytes(vec![0; 64 * 1024 * 1024]));
+        let result = client.send_to_with(&request, &mut Vec::new(), |_, _| Ok(()));
+        assert!(matches!(result, Err(ProxyError::Timeout { phase: Phase::Request })), "{:?}", result.map(|_| ()));
+        drop(client);
+        proxy.join().unwrap();
+    }
+
+    /// Linux drops the connection attempts once the accept queue of a listener is full
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn times_out_connecting() {
+        let listener = socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None).unwrap();
+        listener.bind(&"127.0.0.1:0".parse::<std::net::SocketAddr>().unwrap().into()).unwrap();
+        listener.listen(0).unwrap();
+        let address = listener.local_addr().unwrap().as_socket().unwrap();
+        let _queued = TcpStream::connect(address).unwrap();
+        let config = ClientConfig { connect_timeout: Some(Duration::from_millis(100)), ..ClientConfig::default() };
+        match ProxyClient::connect_with_config(address, config) {
+            Err(err @ ProxyError::ConnectFailed { .. }) => assert_eq!(err.class(), ErrorClass::Timeout),
+            other => panic!("Expected a timed out ConnectFailed, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn reports_the_retries_to_the_retry_hook() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let proxy = thread::spawn(move || {
+            // The first connection is closed before the handshake
+            drop(listener.accept().unwrap());
+            accept_session(&listener, &[]);
+        });
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let retries = Arc::clone(&reported);
+        let config = ClientConfig {
+            retry: RetryPolicy { max_attempts: 2, base_delay: Duration::from_millis(10), ..RetryPolicy::default() },
+            retry_hook: Some(ProgressHook::new(move |message| retries.lock().unwrap().push(message.to_owned()))),
+            ..ClientConfig::default()
+        };
+        ProxyClient::connect_with_config(address, config).unwrap();
+        let reported = reported.lock().unwrap();
+        assert_eq!(reported.len(), 1);
+        assert!(reported[0].starts_with("Attempt 1 of 2 failed: "), "{}", reported[0]);
+        proxy.join().unwrap();
+    }
+}
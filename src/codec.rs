@@ -0,0 +1,121 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+use crate::error::ProxyError;
+
+const HEADER_LENGTH: usize = 4;
+/// Room reserved ahead for a frame still arriving, the buffer grows with the data past this,
+/// so a huge announced length alone doesn't allocate anything big
+const MAX_RESERVE: usize = 64 * 1024;
+
+/// The same framing as `add_headers`/`parse_headers`:
+/// 4 bytes of big-endian length followed by the body
+#[derive(Debug, Clone, Copy)]
+pub struct LengthPrefixedCodec {
+    /// Frames announcing a bigger body fail to decode before it's buffered
+    pub max_frame_length: u64,
+}
+
+impl LengthPrefixedCodec {
+    pub fn new(max_frame_length: u64) -> LengthPrefixedCodec {
+        LengthPrefixedCodec { max_frame_length }
+    }
+}
+
+impl Default for LengthPrefixedCodec {
+    fn default() -> Self {
+        LengthPrefixedCodec::new(u32::MAX as u64)
+    }
+}
+
+impl Decoder for LengthPrefixedCodec {
+    type Item = BytesMut;
+    type Error = ProxyError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<BytesMut>, ProxyError> {
+        if src.len() < HEADER_LENGTH {
+            return Ok(None);
+        }
+        let length = u32::from_be_bytes([src[0], src[1], src[2], src[3]]) as usize;
+        if length as u64 > self.max_frame_length {
+            return Err(ProxyError::FrameTooLarge { length: length as u64, max: self.max_frame_length });
+        }
+        if src.len() < HEADER_LENGTH + length {
+            src.reserve((HEADER_LENGTH + length - src.len()).min(MAX_RESERVE));
+            return Ok(None);
+        }
+        src.advance(HEADER_LENGTH);
+        Ok(Some(src.split_to(length)))
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<BytesMut>, ProxyError> {
+        match self.decode(src)? {
+            Some(frame) => Ok(Some(frame)),
+            None if src.is_empty() => Ok(None),
+            None => Err(ProxyError::UnexpectedEof),
+        }
+    }
+}
+
+impl Encoder<Bytes> for LengthPrefixedCodec {
+    type Error = ProxyError;
+
+    fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> Result<(), ProxyError> {
+        let length = item.len();
+        if length > u32::MAX as usize {
+            return Err(ProxyError::FrameTooLarge { length: length as u64, max: u32::MAX as u64 });
+        }
+        dst.reserve(HEADER_LENGTH + length);
+        dst.put_u32(length as u32);
+        dst.extend_from_slice(&item);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(body: &[u8]) -> BytesMut {
+        let mut frame = BytesMut::new();
+        LengthPrefixedCodec::default().encode(Bytes::copy_from_slice(body), &mut frame).unwrap();
+        frame
+    }
+
+    #[test]
+    fn waits_for_the_whole_frame() {
+        let mut codec = LengthPrefixedCodec::default();
+        let whole = frame(b"hello");
+        let mut src = BytesMut::from(&whole[..2]);
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+        src.extend_from_slice(&whole[2..7]);
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+        src.extend_from_slice(&whole[7..]);
+        src.extend_from_slice(&frame(b"")[..]);
+        assert_eq!(&codec.decode(&mut src).unwrap().unwrap()[..], b"hello");
+        assert_eq!(&codec.decode(&mut src).unwrap().unwrap()[..], b"");
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn refuses_frames_over_the_limit() {
+        let mut codec = LengthPrefixedCodec::new(4);
+        assert!(matches!(codec.decode(&mut frame(b"hello")), Err(ProxyError::FrameTooLarge { length: 5, max: 4 })));
+    }
+
+    #[test]
+    fn announced_length_alone_allocates_little() {
+        let mut codec = LengthPrefixedCodec::new(u64::MAX);
+        let mut src = BytesMut::from(&u32::MAX.to_be_bytes()[..]);
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+        assert!(src.capacity() <= HEADER_LENGTH + MAX_RESERVE);
+    }
+
+    #[test]
+    fn partial_frame_at_the_end_is_an_error() {
+        let mut codec = LengthPrefixedCodec::default();
+        assert_eq!(codec.decode_eof(&mut BytesMut::new()).unwrap(), None);
+        let mut src = frame(b"hello");
+        src.truncate(6);
+        assert!(matches!(codec.decode_eof(&mut src), Err(ProxyError::UnexpectedEof)));
+    }
+}
@@ -0,0 +1,84 @@
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+use crate::auth::Credentials;
+use crate::retry::RetryPolicy;
+#[cfg(feature = "tls")]
+use crate::tls::TlsConfig;
+
+/// Settings of a `ProxyClient` session, the defaults match the plain `connect`
+#[derive(Debug, Clone, Default)]
+pub struct ClientConfig {
+    /// Limit for establishing the TCP connection
+    pub connect_timeout: Option<Duration>,
+    /// Limit for every single read from the proxy
+    pub read_timeout: Option<Duration>,
+    /// Limit for every single write to the proxy
+    pub write_timeout: Option<Duration>,
+    /// Limit for the whole session, from connecting until the bye response
+    pub deadline: Option<Duration>,
+    /// Responses announcing a bigger body, in the frame header or in the Content-Length
+    /// of the metadata, are refused before reading it. Chunked bodies without
+    /// a Content-Length are cut off once they pass the limit.
+    pub max_response_size: Option<u64>,
+    /// Applied to connecting and to every fetch, the session is
+    /// re-established from scratch before each retry
+    pub retry: RetryPolicy,
+    /// Enables TCP keepalive, the probes start after the connection was idle this long
+    /// and repeat at the same interval
+    pub keepalive: Option<Duration>,
+    /// Ping the proxy from a background thread whenever the session was idle this long,
+    /// a proxy that doesn't answer is reconnected before the next request
+    pub heartbeat_interval: Option<Duration>,
+    /// How long to wait for the answer to a ping before treating the proxy as gone,
+    /// the read timeout is used when not set
+    pub dead_peer_timeout: Option<Duration>,
+    /// Answer the server's authentication challenge with these
+    pub credentials: Option<Credentials>,
+    /// Answer a challenge for the plain token over unencrypted TCP too. Without it the token
+    /// is only sent over TLS or a Unix socket, the HMAC challenge is always answered.
+    pub allow_plaintext_token: bool,
+    /// Send the bare version 1 Connect, for servers that refuse the versioned one
+    pub legacy_handshake: bool,
+    /// Receives the progress messages, like the steps of the handshake and the retries.
+    /// Nothing is reported when not set.
+    pub progress: Option<ProgressHook>,
+    /// Receives a message for every failed attempt that is about to be retried,
+    /// they go to the progress hook when not set
+    pub retry_hook: Option<ProgressHook>,
+    /// Talk TLS to the proxy instead of plain TCP
+    #[cfg(feature = "tls")]
+    pub tls: Option<TlsConfig>,
+}
+
+impl ClientConfig {
+    pub(crate) fn report(&self, message: fmt::Arguments<'_>) {
+        if let Some(progress) = &self.progress {
+            (progress.0)(&message.to_string());
+        }
+    }
+
+    pub(crate) fn report_retry(&self, message: fmt::Arguments<'_>) {
+        match &self.retry_hook {
+            Some(retry_hook) => (retry_hook.0)(&message.to_string()),
+            None => self.report(message),
+        }
+    }
+}
+
+/// Callback for the progress messages of a session, the CLI prints them with --verbose
+/// and the retries always
+#[derive(Clone)]
+pub struct ProgressHook(Arc<dyn Fn(&str) + Send + Sync>);
+
+impl ProgressHook {
+    pub fn new<F: Fn(&str) + Send + Sync + 'static>(callback: F) -> ProgressHook {
+        ProgressHook(Arc::new(callback))
+    }
+}
+
+impl fmt::Debug for ProgressHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ProgressHook(..)")
+    }
+}
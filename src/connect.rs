@@ -0,0 +1,169 @@
+use std::io;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+use socket2::{SockRef, TcpKeepalive};
+use crate::error::{ProxyError, Result};
+
+/// Marks the proxy server addresses that are paths of Unix domain sockets
+pub const UNIX_SOCKET_PREFIX: &str = "unix:";
+
+/// How long an attempt gets before the next address is tried in parallel (RFC 8305)
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// The longest keepalive idle time Linux accepts, about 9 hours
+const MAX_KEEPALIVE_IDLE: Duration = Duration::from_secs(32767);
+
+/// The keepalive probes start after `idle` without traffic and repeat at the same interval,
+/// the number of probes is left to the system
+pub fn set_keepalive(socket: &TcpStream, idle: Duration) -> io::Result<()> {
+    let idle = idle.min(MAX_KEEPALIVE_IDLE);
+    let keepalive = TcpKeepalive::new().with_time(idle);
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios",
+              target_os = "freebsd", target_os = "windows"))]
+    let keepalive = keepalive.with_interval(idle);
+    SockRef::from(socket).set_tcp_keepalive(&keepalive)
+}
+
+/// Resolves the address with DNS or /etc/hosts, so both "10.0.0.1:9000"
+/// and "proxy.internal:9000" work
+pub fn resolve(proxy_server_address: &str) -> Result<Vec<SocketAddr>> {
+    let resolve_failed = |err| ProxyError::ResolveFailed { address: proxy_server_address.to_owned(), err };
+    let addresses: Vec<SocketAddr> = proxy_server_address.to_socket_addrs()
+        .map_err(resolve_failed)?
+        .collect();
+    if addresses.is_empty() {
+        return Err(resolve_failed(io::Error::new(io::ErrorKind::NotFound, "No addresses found")));
+    }
+    Ok(interleave_families(addresses))
+}
+
+/// Alternates IPv6 and IPv4 addresses, starting with the family the resolver preferred,
+/// so a broken family doesn't have to time out on every one of its addresses first
+fn interleave_families(addresses: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let preferred_is_ipv6 = addresses[0].is_ipv6();
+    let (mut preferred, mut other): (Vec<_>, Vec<_>) = addresses.into_iter()
+        .partition(|address| address.is_ipv6() == preferred_is_ipv6);
+    let mut result = Vec::with_capacity(preferred.len() + other.len());
+    preferred.reverse();
+    other.reverse();
+    while let Some(address) = preferred.pop() {
+        result.push(address);
+        if let Some(address) = other.pop() {
+            result.push(address);
+        }
+    }
+    result.extend(other.into_iter().rev());
+    result
+}
+
+/// Happy eyeballs style connection: the addresses are tried in order, starting the
+/// next attempt when the previous one fails or takes longer than the attempt delay.
+/// The first connection to succeed wins.
+pub fn connect_any(proxy_server_address: &str, addresses: &[SocketAddr], timeout: Option<Duration>) -> Result<TcpStream> {
+    if let [address] = addresses {
+        return connect_one(*address, timeout)
+            .map_err(|err| connect_failed(proxy_server_address, vec![(*address, err)]));
+    }
+    let (sender, receiver) = mpsc::channel();
+    let mut remaining = addresses.iter();
+    let mut start_next = || match remaining.next() {
+        Some(&address) => {
+            let sender = sender.clone();
+            thread::spawn(move || {
+                // The receiver is gone once another attempt won, so the result is dropped
+                let _ = sender.send((address, connect_one(address, timeout)));
+            });
+            true
+        }
+        None => false,
+    };
+    start_next();
+    let mut pending = 1;
+    let mut failures = Vec::new();
+    while pending > 0 {
+        match receiver.recv_timeout(CONNECTION_ATTEMPT_DELAY) {
+            Ok((_, Ok(socket))) => return Ok(socket),
+            Ok((address, Err(err))) => {
+                pending -= 1;
+                failures.push((address, err));
+                if start_next() {
+                    pending += 1;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                if start_next() {
+                    pending += 1;
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    Err(connect_failed(proxy_server_address, failures))
+}
+
+/// Lists every attempted address with its error, when they all timed out
+/// the error counts as a timeout of the connect phase
+fn connect_failed(proxy_server_address: &str, failures: Vec<(SocketAddr, io::Error)>) -> ProxyError {
+    ProxyError::ConnectFailed { address: proxy_server_address.to_owned(), attempts: failures }
+}
+
+fn connect_one(address: SocketAddr, timeout: Option<Duration>) -> io::Result<TcpStream> {
+    match timeout {
+        Some(timeout) => TcpStream::connect_timeout(&address, timeout),
+        None => TcpStream::connect(address),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use crate::retry::ErrorClass;
+    use super::*;
+
+    fn addresses(list: &[&str]) -> Vec<SocketAddr> {
+        list.iter().map(|address| address.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn alternates_the_families_starting_with_the_first() {
+        let resolved = addresses(&["[::1]:1", "[::2]:1", "[::3]:1", "10.0.0.1:1", "10.0.0.2:1"]);
+        assert_eq!(
+            interleave_families(resolved),
+            addresses(&["[::1]:1", "10.0.0.1:1", "[::2]:1", "10.0.0.2:1", "[::3]:1"]),
+        );
+        let resolved = addresses(&["10.0.0.1:1", "10.0.0.2:1", "10.0.0.3:1", "[::1]:1"]);
+        assert_eq!(
+            interleave_families(resolved),
+            addresses(&["10.0.0.1:1", "[::1]:1", "10.0.0.2:1", "10.0.0.3:1"]),
+        );
+        let resolved = addresses(&["10.0.0.1:1", "10.0.0.2:1"]);
+        assert_eq!(interleave_families(resolved.clone()), resolved);
+    }
+
+    #[test]
+    fn names_the_address_that_refused() {
+        // Nothing listens on the port once the listener is gone
+        let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        match connect_any("localhost:1234", &[address], None) {
+            Err(ProxyError::ConnectFailed { address: name, attempts }) => {
+                assert_eq!(name, "localhost:1234");
+                assert_eq!(attempts.len(), 1);
+                assert_eq!(attempts[0].0, address);
+            }
+            other => panic!("Expected ConnectFailed, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn keeps_every_address_when_they_all_timed_out() {
+        let timed_out = || io::Error::from(io::ErrorKind::TimedOut);
+        let attempts = vec![("[::1]:1".parse().unwrap(), timed_out()), ("10.0.0.1:1".parse().unwrap(), timed_out())];
+        let err = connect_failed("proxy:1", attempts);
+        assert_eq!(err.class(), ErrorClass::Timeout);
+        assert!(matches!(&err, ProxyError::ConnectFailed { attempts, .. } if attempts.len() == 2));
+        let refused = vec![("10.0.0.1:1".parse().unwrap(), io::Error::from(io::ErrorKind::ConnectionRefused))];
+        assert_eq!(connect_failed("proxy:1", refused).class(), ErrorClass::Io);
+    }
+}
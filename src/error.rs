@@ -0,0 +1,143 @@
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use crate::message::Message;
+
+pub type Result<T> = std::result::Result<T, ProxyError>;
+
+/// Everything that can go wrong while talking to the proxy server
+#[derive(Debug)]
+pub enum ProxyError {
+    Io(io::Error),
+    /// The proxy closed the connection in the middle of a frame
+    UnexpectedEof,
+    /// The proxy answered the Connect message with something other than Accept
+    HandshakeRejected { got: Message },
+    /// The proxy answered the BYE message with something other than BYE
+    ByeMismatch { got: Message },
+    /// The frame doesn't fit into the u32 length header,
+    /// or the proxy announced a frame over the configured limit
+    FrameTooLarge { length: u64, max: u64 },
+    BadAddress(String),
+    /// The proxy server address couldn't be resolved
+    ResolveFailed { address: String, err: io::Error },
+    /// None of the resolved addresses accepted the connection.
+    /// When every attempt timed out it's classed as a timeout of the connect phase.
+    ConnectFailed { address: String, attempts: Vec<(SocketAddr, io::Error)> },
+    /// The proxy answered with an error frame instead of the response body
+    Remote { code: u32, message: String },
+    /// Invalid TLS setup, like unreadable certificates or a bad server name
+    Tls(String),
+    /// The request can't be put into a frame, like a header with a line break
+    InvalidRequest(String),
+    /// The proxy sent a message that doesn't fit at this point of the session,
+    /// like a second metadata frame before the body
+    UnexpectedMessage { got: Message },
+    /// The proxy requires authentication and refused the credentials, or none were given
+    AuthFailed(String),
+    /// One of the configured timeouts or the overall deadline expired
+    Timeout { phase: Phase },
+}
+
+/// The step of the session, used to tell where a timeout happened
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Connect,
+    Handshake,
+    Request,
+    Response,
+    Heartbeat,
+    Bye,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Phase::Connect => "connect",
+            Phase::Handshake => "handshake",
+            Phase::Request => "request",
+            Phase::Response => "response",
+            Phase::Heartbeat => "heartbeat",
+            Phase::Bye => "bye",
+        };
+        f.write_str(name)
+    }
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProxyError::Io(err) => write!(f, "I/O error: {}", err),
+            ProxyError::UnexpectedEof => write!(f, "The proxy closed the connection unexpectedly"),
+            ProxyError::HandshakeRejected { got } =>
+                write!(f, "The proxy rejected the handshake, got {}", got),
+            ProxyError::ByeMismatch { got } =>
+                write!(f, "Unexpected response to the bye message: {}", got),
+            ProxyError::FrameTooLarge { length, max } =>
+                write!(f, "Frame of {} bytes exceeds the maximum of {} bytes", length, max),
+            ProxyError::BadAddress(address) =>
+                write!(f, "Couldn't parse the proxy address {:?}", address),
+            ProxyError::ResolveFailed { address, err } =>
+                write!(f, "Couldn't resolve the proxy address {:?}: {}", address, err),
+            ProxyError::ConnectFailed { address, attempts } => {
+                write!(f, "Couldn't connect to {:?}, tried", address)?;
+                for (index, (socket_address, err)) in attempts.iter().enumerate() {
+                    let separator = if index == 0 { "" } else { "," };
+                    write!(f, "{} {} ({})", separator, socket_address, err)?;
+                }
+                Ok(())
+            }
+            ProxyError::Remote { code, message } => write!(f, "The proxy reported error {}: {}", code, message),
+            ProxyError::Tls(message) => write!(f, "TLS error: {}", message),
+            ProxyError::InvalidRequest(message) => write!(f, "Invalid request: {}", message),
+            ProxyError::UnexpectedMessage { got } => write!(f, "Unexpected message from the proxy: {}", got),
+            ProxyError::AuthFailed(message) => write!(f, "Authentication failed: {}", message),
+            ProxyError::Timeout { phase } => write!(f, "Timed out during the {} phase", phase),
+        }
+    }
+}
+
+impl std::error::Error for ProxyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProxyError::Io(err) => Some(err),
+            ProxyError::ResolveFailed { err, .. } => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl ProxyError {
+    /// For the `Read` and `Write` implementations, I/O errors are passed as they are
+    pub fn into_io(self) -> io::Error {
+        match self {
+            ProxyError::Io(err) => err,
+            ProxyError::UnexpectedEof => io::Error::new(io::ErrorKind::UnexpectedEof, ProxyError::UnexpectedEof),
+            other => io::Error::other(other),
+        }
+    }
+
+    /// Turns the I/O timeouts into `Timeout` errors naming the phase
+    pub fn in_phase(self, phase: Phase) -> ProxyError {
+        match self {
+            ProxyError::Io(err) if is_timeout(&err) => ProxyError::Timeout { phase },
+            other => other,
+        }
+    }
+}
+
+/// Depending on the platform a timed out socket read reports either of these
+pub(crate) fn is_timeout(err: &io::Error) -> bool {
+    matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+impl From<io::Error> for ProxyError {
+    /// Unwraps the errors `into_io` wrapped
+    fn from(err: io::Error) -> Self {
+        if err.get_ref().is_some_and(|inner| inner.is::<ProxyError>()) {
+            let inner = err.into_inner().expect("Checked above");
+            return *inner.downcast::<ProxyError>().expect("Checked above");
+        }
+        ProxyError::Io(err)
+    }
+}
@@ -0,0 +1,33 @@
+//! Client for the proxy server speaking a simple length-prefixed protocol
+//! over TCP: every frame is a 4 byte big-endian length followed by the body.
+
+#[cfg(feature = "async")]
+pub mod async_client;
+pub mod atomic_file;
+pub mod auth;
+pub mod client;
+#[cfg(feature = "async")]
+pub mod codec;
+pub mod config;
+pub mod connect;
+pub mod error;
+pub mod message;
+pub mod protocol;
+pub mod repl;
+pub mod request;
+pub mod retry;
+#[cfg(feature = "tls")]
+pub mod tls;
+mod transport;
+
+#[cfg(feature = "async")]
+pub use async_client::AsyncProxyClient;
+pub use auth::Credentials;
+pub use client::{ProxyClient, Response};
+pub use config::{ClientConfig, ProgressHook};
+pub use error::{Phase, ProxyError, Result};
+pub use message::{Message, Metadata, RequestHead};
+pub use request::{Request, RequestBody};
+pub use retry::{ErrorClass, RetryPolicy};
+#[cfg(feature = "tls")]
+pub use tls::TlsConfig;
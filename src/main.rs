@@ -1,137 +1,721 @@
-use std::fs::File;
-use std::io::{Read, Write};
-use std::net::{SocketAddr, TcpStream};
-use std::ops::Add;
-use clap::{App, Arg};
-
-const CONNECT_MESSAGE: &'static str = "Connect";
-const ACCEPT_RESPONSE: &'static str = "Accept";
-const REQUEST_PREFIX: &'static str = "GET:";
-const BYE_MESSAGE: &'static str = "BYE";
-const BYE_RESPONSE: &'static str = "BYE";
-const MAX_BATCH_SIZE: usize = 500;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use clap::{App, Arg, ArgMatches, ErrorKind, SubCommand};
+use rust_proxy_tcp_client::atomic_file::AtomicFile;
+use rust_proxy_tcp_client::repl;
+use rust_proxy_tcp_client::{
+    ClientConfig, Credentials, ErrorClass, Metadata, ProgressHook, ProxyClient, ProxyError, Request, RequestBody, Result,
+    RetryPolicy,
+};
+#[cfg(feature = "tls")]
+use rust_proxy_tcp_client::{tls, TlsConfig};
+
+const MAX_FILE_NAME_URL_LENGTH: usize = 200;
 
 fn main() {
     let app = App::new("TCP Client for the proxy server")
         .author("Ruben Kostandyan @KoStard")
         .about("Connects to the proxy server, sends the given \
                 URL to it and receives the response back, \
-                printing to the standard output")
+                writing it to the target file or the standard output. \
+                The retried attempts are reported on the standard error, \
+                with --verbose the progress messages too")
         .arg(Arg::with_name("proxy-server")
             .short("p")
             .long("proxy-server")
-            .help("The proxy server address")
+            .help("The proxy server address, as ip:port, host:port or unix:/path/to/socket")
             .takes_value(true)
             .required(true))
         .arg(Arg::with_name("url")
             .long("url")
-            .help("The target URL you are trying to read from with the proxy")
+            .help("The target URL you are trying to read from with the proxy, \
+                   can be repeated to fetch several URLs over one session")
             .takes_value(true)
-            .required(true))
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("url-list")
+            .long("url-list")
+            .help("File with the URLs to fetch, one per line")
+            .takes_value(true))
         .arg(Arg::with_name("target-file")
             .long("target-file")
             .short("f")
-            .help("The target file to write the proxy server response into")
+            .help("The target file to write the proxy server response into, \
+                   the standard output is used when it's \"-\" or omitted")
             .takes_value(true)
-            .required(true))
-        .get_matches();
-    let proxy_server_address_raw = app.value_of("proxy-server").expect("Proxy server not provided");
-    let url = app.value_of("url").expect("Destination URL not specified");
-    let target_file_path = app.value_of("target-file").expect("Target file not specified");
+            .conflicts_with("output-dir"))
+        .arg(Arg::with_name("output-dir")
+            .long("output-dir")
+            .short("o")
+            .help("The directory to write the responses into, one file per URL")
+            .takes_value(true))
+        .arg(Arg::with_name("parallel")
+            .long("parallel")
+            .help("Spread the URLs over this many sessions, each on its own connection. \
+                   A failed URL doesn't stop the others and a report of every URL is printed at the end")
+            .takes_value(true)
+            .value_name("N")
+            .requires("output-dir")
+            .conflicts_with("dump-headers")
+            .validator(|value| match value.parse::<usize>() {
+                Ok(parallel) if parallel > 0 => Ok(()),
+                _ => Err(format!("{:?} is not a positive number", value)),
+            }))
+        .arg(Arg::with_name("method")
+            .long("method")
+            .short("X")
+            .help("The HTTP method, GET by default or POST when a body is given")
+            .takes_value(true))
+        .arg(Arg::with_name("header")
+            .long("header")
+            .short("H")
+            .help("Request header as \"Name: Value\", can be repeated")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .validator(|value| parse_header(&value).map(|_| ())))
+        .arg(Arg::with_name("data")
+            .long("data")
+            .short("d")
+            .help("The request body")
+            .takes_value(true)
+            .conflicts_with("data-file"))
+        .arg(Arg::with_name("data-file")
+            .long("data-file")
+            .help("File to stream as the request body")
+            .takes_value(true)
+            .value_name("FILE"))
+        .arg(Arg::with_name("include-headers")
+            .long("include-headers")
+            .short("i")
+            .help("Write the response status and headers before the body, \
+                   when the proxy sends them"))
+        .arg(Arg::with_name("dump-headers")
+            .long("dump-headers")
+            .help("File to write the response status and headers into, \
+                   \"-\" for the standard output")
+            .takes_value(true)
+            .value_name("FILE"))
+        .arg(Arg::with_name("verbose")
+            .long("verbose")
+            .short("v")
+            .help("Print the progress of the session to the standard error"))
+        .arg(seconds_arg("connect-timeout", "Seconds to wait for the TCP connection"))
+        .arg(seconds_arg("read-timeout", "Seconds to wait for each read from the proxy"))
+        .arg(seconds_arg("write-timeout", "Seconds to wait for each write to the proxy"))
+        .arg(seconds_arg("deadline", "Seconds the whole session is allowed to take"))
+        .arg(seconds_arg("keepalive", "Enable TCP keepalive, probing after this many idle seconds"))
+        .arg(seconds_arg("heartbeat", "Ping the proxy whenever the session was idle this many seconds, \
+                                          reconnecting before the next request when it doesn't answer"))
+        .arg(seconds_arg("dead-peer-timeout", "Seconds to wait for the answer to a ping before reconnecting"))
+        .arg(Arg::with_name("max-response-size")
+            .long("max-response-size")
+            .help("Refuse responses bigger than this, in bytes or with a K, M or G suffix")
+            .takes_value(true)
+            .value_name("SIZE")
+            .validator(|value| parse_size(&value).map(|_| ())))
+        .arg(Arg::with_name("token")
+            .long("token")
+            .help("Shared token for the proxies that require authentication")
+            .takes_value(true)
+            .env("PROXY_TOKEN")
+            .hide_env_values(true))
+        .arg(Arg::with_name("token-file")
+            .long("token-file")
+            .help("File containing the shared token")
+            .takes_value(true)
+            .value_name("FILE")
+            .conflicts_with("token"))
+        .arg(Arg::with_name("allow-plaintext-token")
+            .long("allow-plaintext-token")
+            .help("Send the token itself when the proxy asks for it over plain TCP, \
+                   by default it's only sent over TLS or a Unix socket"))
+        .arg(Arg::with_name("legacy-handshake")
+            .long("legacy-handshake")
+            .help("Send the bare version 1 Connect message, for proxies that refuse the versioned one"))
+        .arg(Arg::with_name("max-attempts")
+            .long("max-attempts")
+            .help("How many times to try connecting and fetching each URL before giving up")
+            .takes_value(true)
+            .validator(|value| match value.parse::<u32>() {
+                Ok(attempts) if attempts > 0 => Ok(()),
+                _ => Err(format!("{:?} is not a positive number", value)),
+            }))
+        .arg(seconds_arg("retry-delay", "Seconds to wait before the first retry, doubled after each one"))
+        .arg(seconds_arg("retry-max-delay", "Upper limit of the delay between the retries"))
+        .arg(Arg::with_name("retry-jitter")
+            .long("retry-jitter")
+            .help("Fraction of the retry delay, from 0 to 1, that is randomly cut off")
+            .takes_value(true)
+            .validator(|value| match value.parse::<f64>() {
+                Ok(jitter) if (0.0..=1.0).contains(&jitter) => Ok(()),
+                _ => Err(format!("{:?} is not a number between 0 and 1", value)),
+            }))
+        .arg(Arg::with_name("retry-on")
+            .long("retry-on")
+            .help("Comma separated error classes to retry: io, timeout, eof, protocol, remote, local")
+            .takes_value(true)
+            .use_delimiter(true)
+            .validator(|value| value.parse::<ErrorClass>().map(|_| ())))
+        .subcommand(SubCommand::with_name("repl")
+            .about("Connects and reads commands from the standard input to explore the session frame by frame: \
+                    get <url>, raw <text>, ping, save <file> and bye"));
+    #[cfg(feature = "tls")]
+    let app = tls_args(app);
+    let app = app.get_matches();
+    let result = match (app.subcommand_name(), app.value_of("parallel")) {
+        (Some("repl"), _) => run_repl(&app),
+        (None, Some(parallel)) => run_batch(&app, parallel.parse().expect("Validated by clap")),
+        _ => run(&app),
+    };
+    if let Err(err) = result {
+        eprintln!("Error: {}", err);
+        process::exit(exit_code(&err));
+    }
+}
+
+fn run(app: &ArgMatches) -> Result<()> {
+    let proxy_server_address = app.value_of("proxy-server").expect("Proxy server not provided");
+    let urls = collect_urls(app)?;
+    let targets = target_paths(app, &urls);
+    if let Some(output_dir) = app.value_of("output-dir") {
+        fs::create_dir_all(output_dir)?;
+    }
+
+    let mut client = ProxyClient::connect_with_config(proxy_server_address, client_config(app)?)?;
+
+    let mut header_dump: Option<Box<dyn Write>> = match app.value_of("dump-headers") {
+        Some("-") => Some(Box::new(io::stdout())),
+        Some(path) => Some(Box::new(BufWriter::new(File::create(path)?))),
+        None => None,
+    };
+    let include_headers = app.is_present("include-headers");
+    let mut on_metadata = |metadata: &Metadata, out: &mut dyn Write| {
+        if let Some(header_dump) = header_dump.as_mut() {
+            metadata.write_to(header_dump)?;
+        }
+        if include_headers {
+            metadata.write_to(out)?;
+        }
+        Ok(())
+    };
+
+    // The files only get their names once the whole session succeeded,
+    // on any error the temporary files are removed when dropped
+    let mut completed_files = Vec::new();
+    for (url, target) in urls.iter().zip(targets) {
+        match target {
+            Target::Stdout => {
+                let mut writer = BufWriter::new(io::stdout().lock());
+                client.send_to_with(&build_request(app, url), &mut writer, &mut on_metadata)?;
+                writer.flush()?;
+            }
+            Target::File(target_file_path) => {
+                let mut writer = BufWriter::new(AtomicFile::create(target_file_path)?);
+                client.send_to_with(&build_request(app, url), &mut writer, &mut on_metadata)?;
+                completed_files.push(writer.into_inner().map_err(|err| err.into_error())?);
+            }
+        }
+    }
+
+    client.bye()?;
+    if let Some(header_dump) = header_dump.as_mut() {
+        header_dump.flush()?;
+    }
+    for file in completed_files {
+        file.commit()?;
+    }
+    Ok(())
+}
+
+/// What happened to one URL of a batch
+struct Outcome {
+    url: String,
+    path: PathBuf,
+    /// The length of the body
+    result: Result<u64>,
+    elapsed: Duration,
+}
+
+/// Spreads the URLs over `parallel` sessions. Unlike a single session, every file
+/// is kept as soon as it's complete and a failed URL doesn't stop the others.
+fn run_batch(app: &ArgMatches, parallel: usize) -> Result<()> {
+    let proxy_server_address = app.value_of("proxy-server").expect("Proxy server not provided");
+    let urls = collect_urls(app)?;
+    let output_dir = Path::new(app.value_of("output-dir").expect("Required by clap"));
+    fs::create_dir_all(output_dir)?;
+    let config = client_config(app)?;
+    let started = Instant::now();
+    let outcomes = fetch_in_parallel(app, proxy_server_address, &config, &urls, output_dir, parallel);
+    print_report(&mut io::stdout().lock(), &outcomes, started.elapsed())?;
+    // The report shows every failure, the exit code tells about the first one
+    match outcomes.into_iter().find_map(|outcome| outcome.result.err()) {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+/// Runs the sessions and collects the outcomes in the order of the URLs
+fn fetch_in_parallel(
+    app: &ArgMatches,
+    proxy_server_address: &str,
+    config: &ClientConfig,
+    urls: &[String],
+    output_dir: &Path,
+    parallel: usize,
+) -> Vec<Outcome> {
+    let next_url = AtomicUsize::new(0);
+    let mut outcomes: Vec<(usize, Outcome)> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..parallel.min(urls.len()))
+            .map(|_| scope.spawn(|| batch_session(app, proxy_server_address, config, urls, output_dir, &next_url)))
+            .collect();
+        workers.into_iter().flat_map(|worker| worker.join().expect("Batch session panicked")).collect()
+    });
+    outcomes.sort_by_key(|(index, _)| *index);
+    outcomes.into_iter().map(|(_, outcome)| outcome).collect()
+}
+
+/// One session of the batch, taking the next URL until none are left.
+/// Connects on the first URL, and again after a failure that may have broken the connection.
+fn batch_session(
+    app: &ArgMatches,
+    proxy_server_address: &str,
+    config: &ClientConfig,
+    urls: &[String],
+    output_dir: &Path,
+    next_url: &AtomicUsize,
+) -> Vec<(usize, Outcome)> {
+    let mut client = None;
+    let mut outcomes = Vec::new();
+    loop {
+        let index = next_url.fetch_add(1, Ordering::Relaxed);
+        let url = match urls.get(index) {
+            Some(url) => url,
+            None => break,
+        };
+        let path = output_dir.join(output_file_name(index, url));
+        let started = Instant::now();
+        if client.is_none() {
+            match ProxyClient::connect_with_config(proxy_server_address, config.clone()) {
+                Ok(connected) => client = Some(connected),
+                Err(err) => {
+                    let elapsed = started.elapsed();
+                    outcomes.push((index, Outcome { url: url.clone(), path, result: Err(err), elapsed }));
+                    continue;
+                }
+            }
+        }
+        let result = fetch_into_file(app, client.as_mut().expect("Connected above"), url, &path);
+        // The proxy answers an error frame instead of the body, the session is still fine after it
+        if matches!(result, Err(ref err) if !matches!(err, ProxyError::Remote { .. })) {
+            client = None;
+        }
+        outcomes.push((index, Outcome { url: url.clone(), path, result, elapsed: started.elapsed() }));
+    }
+    if let Some(client) = client {
+        if let Err(err) = client.bye() {
+            eprintln!("Error ending a batch session: {}", err);
+        }
+    }
+    outcomes
+}
+
+fn fetch_into_file(app: &ArgMatches, client: &mut ProxyClient, url: &str, path: &Path) -> Result<u64> {
+    let include_headers = app.is_present("include-headers");
+    let mut writer = BufWriter::new(AtomicFile::create(path)?);
+    let response = client.send_to_with(&build_request(app, url), &mut writer, |metadata, out| {
+        if include_headers {
+            metadata.write_to(out)?;
+        }
+        Ok(())
+    })?;
+    writer.into_inner().map_err(|err| err.into_error())?.commit()?;
+    Ok(response.body_length)
+}
+
+/// One line per URL in the order of the list, followed by the totals
+fn print_report<W: Write>(out: &mut W, outcomes: &[Outcome], elapsed: Duration) -> io::Result<()> {
+    let mut succeeded = 0;
+    let mut total_bytes = 0;
+    for outcome in outcomes {
+        let seconds = outcome.elapsed.as_secs_f64();
+        match &outcome.result {
+            Ok(bytes) => {
+                succeeded += 1;
+                total_bytes += bytes;
+                writeln!(out, "OK     {:>12} bytes {:>8.2}s  {} -> {}", bytes, seconds, outcome.url, outcome.path.display())?;
+            }
+            Err(err) => writeln!(out, "FAILED {:>18} {:>8.2}s  {}: {}", "", seconds, outcome.url, err)?,
+        }
+    }
+    writeln!(
+        out,
+        "{} succeeded, {} failed, {} bytes in {:.2}s",
+        succeeded,
+        outcomes.len() - succeeded,
+        total_bytes,
+        elapsed.as_secs_f64(),
+    )
+}
 
-    let proxy_server_address: SocketAddr = proxy_server_address_raw
-        .parse()
-        .expect("Couldn't parse the proxy address");
-    let mut socket = TcpStream::connect(proxy_server_address)
-        .expect("Failed to bind to the UDP socket");
+/// Reads the commands from the standard input, the session output goes to the standard output
+fn run_repl(app: &ArgMatches) -> Result<()> {
+    let proxy_server_address = app.value_of("proxy-server").expect("Proxy server not provided");
+    let client = ProxyClient::connect_with_config(proxy_server_address, client_config(app)?)?;
+    repl::run(client, io::stdin().lock(), io::stdout())
+}
 
-    println!("Sending connect");
-    send_message(CONNECT_MESSAGE.to_owned(), &mut socket);
-    println!("Waiting for acceptance");
-    assert_eq!(response_to_string(load_tcp_message(&mut socket)), ACCEPT_RESPONSE);
+fn client_config(app: &ArgMatches) -> Result<ClientConfig> {
+    Ok(ClientConfig {
+        connect_timeout: seconds_value(app, "connect-timeout"),
+        read_timeout: seconds_value(app, "read-timeout"),
+        write_timeout: seconds_value(app, "write-timeout"),
+        deadline: seconds_value(app, "deadline"),
+        keepalive: seconds_value(app, "keepalive"),
+        heartbeat_interval: seconds_value(app, "heartbeat"),
+        dead_peer_timeout: seconds_value(app, "dead-peer-timeout"),
+        max_response_size: app.value_of("max-response-size")
+            .map(|value| parse_size(value).expect("Validated by clap")),
+        retry: retry_policy(app),
+        credentials: credentials(app)?,
+        allow_plaintext_token: app.is_present("allow-plaintext-token"),
+        legacy_handshake: app.is_present("legacy-handshake"),
+        progress: app.is_present("verbose").then(|| ProgressHook::new(|message| eprintln!("{}", message))),
+        retry_hook: Some(ProgressHook::new(|message| eprintln!("{}", message))),
+        #[cfg(feature = "tls")]
+        tls: tls_config(app),
+    })
+}
 
-    let mut file = File::create(target_file_path).expect("Couldn't create the file");
-    println!("Sending the URL");
-    send_message(generate_request_from_url(url), &mut socket);
-    println!("Waiting for response");
-    let main_response = load_tcp_message(&mut socket);
-    file.write_all(main_response.as_slice()).expect("Couldn't write into the file");
+#[cfg(feature = "tls")]
+fn tls_args<'a>(app: App<'a, 'a>) -> App<'a, 'a> {
+    app.arg(Arg::with_name("tls")
+            .long("tls")
+            .help("Connect to the proxy over TLS"))
+        .arg(Arg::with_name("tls-ca")
+            .long("tls-ca")
+            .help("PEM file with the CA certificates to trust instead of the built-in roots. \
+                   A self-signed proxy certificate can't be its own CA, trust it with --tls-pin instead")
+            .takes_value(true)
+            .requires("tls"))
+        .arg(Arg::with_name("tls-server-name")
+            .long("tls-server-name")
+            .help("Name to use for SNI and certificate verification instead of the proxy host")
+            .takes_value(true)
+            .requires("tls"))
+        .arg(Arg::with_name("tls-cert")
+            .long("tls-cert")
+            .help("PEM client certificate chain for authenticating to the proxy")
+            .takes_value(true)
+            .requires_all(&["tls", "tls-key"]))
+        .arg(Arg::with_name("tls-key")
+            .long("tls-key")
+            .help("PEM private key of the client certificate")
+            .takes_value(true)
+            .requires_all(&["tls", "tls-cert"]))
+        .arg(Arg::with_name("tls-pin")
+            .long("tls-pin")
+            .help("Hex SHA-256 digest of an accepted server certificate, can be repeated. \
+                   Without --tls-ca a matching digest replaces the verification of the certificate chain \
+                   and the name, which is how a self-signed proxy certificate is trusted")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .requires("tls")
+            .validator(|value| tls::parse_pin(&value).map(|_| ())))
+}
 
-    println!("Sending bye message");
-    send_message(BYE_MESSAGE.to_owned(), &mut socket);
-    println!("Waiting for bye response");
-    assert_eq!(response_to_string(load_tcp_message(&mut socket)), BYE_RESPONSE);
+#[cfg(feature = "tls")]
+fn tls_config(app: &ArgMatches) -> Option<TlsConfig> {
+    if !app.is_present("tls") {
+        return None;
+    }
+    Some(TlsConfig {
+        ca_file: app.value_of("tls-ca").map(PathBuf::from),
+        server_name: app.value_of("tls-server-name").map(str::to_owned),
+        client_cert: app.value_of("tls-cert").map(PathBuf::from),
+        client_key: app.value_of("tls-key").map(PathBuf::from),
+        pinned_sha256: app.values_of("tls-pin")
+            .map(|pins| pins.map(|pin| tls::parse_pin(pin).expect("Validated by clap")).collect())
+            .unwrap_or_default(),
+    })
 }
 
-fn generate_request_from_url(url: &str) -> String {
-    String::from(REQUEST_PREFIX)
-        .add(url)
+/// --token-file wins over the PROXY_TOKEN environment variable
+fn credentials(app: &ArgMatches) -> Result<Option<Credentials>> {
+    if let Some(path) = app.value_of("token-file") {
+        return Ok(Some(Credentials::from_file(Path::new(path))?));
+    }
+    Ok(app.value_of("token").map(Credentials::new))
 }
 
-fn response_to_string(content: Vec<u8>) -> String {
-    String::from_utf8_lossy(content.as_slice()).to_string()
+fn seconds_arg<'a>(name: &'a str, help: &'a str) -> Arg<'a, 'a> {
+    Arg::with_name(name)
+        .long(name)
+        .help(help)
+        .takes_value(true)
+        .value_name("SECONDS")
+        .validator(|value| parse_seconds(&value).map(|_| ()))
 }
 
-fn send_message(message: String, socket: &mut TcpStream) {
-    // Maybe we can retry in case of failures
-    let mut index = 0;
-    let buf = add_headers(message.as_bytes());
-    while index < buf.len() {
-        let count = socket.write(&buf[index..])
-            .expect("Failed sending a message to the proxy");
-        index += count;
+fn seconds_value(app: &ArgMatches, name: &str) -> Option<Duration> {
+    app.value_of(name).map(|value| parse_seconds(value).expect("Validated by clap"))
+}
+
+/// A positive number of seconds that fits into a `Duration`, like "0.5" or "30"
+fn parse_seconds(value: &str) -> std::result::Result<Duration, String> {
+    value.parse::<f64>()
+        .ok()
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+        .filter(|duration| !duration.is_zero())
+        .ok_or_else(|| format!("{:?} is not a positive number of seconds", value))
+}
+
+/// "1500", "64K", "10M" and "2G", the suffixes are powers of 1024
+fn parse_size(value: &str) -> std::result::Result<u64, String> {
+    let (digits, multiplier) = match value.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&value[..value.len() - 1], 1 << 10),
+        Some('M') => (&value[..value.len() - 1], 1 << 20),
+        Some('G') => (&value[..value.len() - 1], 1 << 30),
+        _ => (value, 1),
+    };
+    digits.parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .ok_or_else(|| format!("{:?} is not a size", value))
+}
+
+fn retry_policy(app: &ArgMatches) -> RetryPolicy {
+    let mut policy = RetryPolicy::default();
+    if let Some(max_attempts) = app.value_of("max-attempts") {
+        policy.max_attempts = max_attempts.parse().expect("Validated by clap");
+    }
+    if let Some(base_delay) = seconds_value(app, "retry-delay") {
+        policy.base_delay = base_delay;
+    }
+    if let Some(max_delay) = seconds_value(app, "retry-max-delay") {
+        policy.max_delay = max_delay;
+    }
+    if let Some(jitter) = app.value_of("retry-jitter") {
+        policy.jitter = jitter.parse().expect("Validated by clap");
+    }
+    if let Some(classes) = app.values_of("retry-on") {
+        policy.retryable = classes.map(|class| class.parse().expect("Validated by clap")).collect();
     }
+    policy
 }
 
-fn add_headers(message: &[u8]) -> Vec<u8> {
-    let length = message.len();
-    if length > u32::MAX as usize {
-        panic!("Maximum allowed length is {}", u32::MAX);
+/// The same method, headers and body are sent to every URL
+fn build_request(app: &ArgMatches, url: &str) -> Request {
+    let body = match (app.value_of("data"), app.value_of("data-file")) {
+        (Some(data), _) => RequestBody::Bytes(data.as_bytes().to_vec()),
+        (None, Some(path)) => RequestBody::File(PathBuf::from(path)),
+        (None, None) => RequestBody::Empty,
+    };
+    let default_method = if body == RequestBody::Empty { "GET" } else { "POST" };
+    let mut request = Request::new(app.value_of("method").unwrap_or(default_method), url).body(body);
+    for header in app.values_of("header").into_iter().flatten() {
+        let (name, value) = parse_header(header).expect("Validated by clap");
+        request = request.header(name, value);
     }
-    let length_bytes = (length as u32).to_be_bytes();
-    let mut new_message = Vec::new();
-    new_message.extend(length_bytes);
-    new_message.extend(message);
-    return new_message;
+    request
 }
 
-fn parse_headers(message: Vec<u8>) -> (u32, Vec<u8>) {
-    (u32::from_be_bytes([message[0], message[1], message[2], message[3]]),
-     message[4..].to_vec())
+/// "Name: Value", the spaces around the value are dropped
+fn parse_header(header: &str) -> std::result::Result<(&str, &str), String> {
+    match header.split_once(':') {
+        Some((name, value)) if !name.trim().is_empty() => Ok((name.trim(), value.trim())),
+        _ => Err(format!("{:?} is not a \"Name: Value\" header", header)),
+    }
+}
+
+/// URLs given with --url come first, followed by the ones from --url-list
+fn collect_urls(app: &ArgMatches) -> Result<Vec<String>> {
+    let mut urls: Vec<String> = app.values_of("url")
+        .map(|values| values.map(str::to_owned).collect())
+        .unwrap_or_default();
+    if let Some(url_list_path) = app.value_of("url-list") {
+        urls.extend(read_url_list(url_list_path)?);
+    }
+    if urls.is_empty() {
+        clap::Error::with_description("No URLs to fetch", ErrorKind::EmptyValue).exit();
+    }
+    Ok(urls)
 }
 
-/// Using custom protocol here
-/// First 4 bytes should be responsible for showing the length of the request
-fn load_tcp_message(stream: &mut TcpStream) -> Vec<u8> {
-    println!("Reading TCP message from {:?}", stream);
-    let mut overall_message = Vec::new();
-    let (overall_length, current_body) = tcp_read_with_headers(stream);
-    overall_message.extend(current_body);
-    while overall_message.len() < overall_length as usize {
-        println!("One Read {} {}", overall_message.len(), overall_length);
-        overall_message.extend(one_tcp_read(stream));
+/// Skips blank lines and lines starting with '#'
+fn read_url_list(path: &str) -> Result<Vec<String>> {
+    let content = fs::read_to_string(path)?;
+    Ok(content.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_owned)
+        .collect())
+}
+
+/// Where a response goes
+enum Target {
+    Stdout,
+    File(PathBuf),
+}
+
+fn target_paths(app: &ArgMatches, urls: &[String]) -> Vec<Target> {
+    if let Some(output_dir) = app.value_of("output-dir") {
+        let output_dir = Path::new(output_dir);
+        return urls.iter()
+            .enumerate()
+            .map(|(index, url)| Target::File(output_dir.join(output_file_name(index, url))))
+            .collect();
+    }
+    if urls.len() > 1 {
+        clap::Error::with_description(
+            "Several URLs need --output-dir, a single target file or the standard output can only take one",
+            ErrorKind::ArgumentConflict,
+        ).exit();
     }
-    if overall_message.len() > overall_length as usize {
-        overall_message[..overall_length as usize].to_vec()
-    } else {
-        overall_message
+    match app.value_of("target-file") {
+        None | Some("-") => vec![Target::Stdout],
+        Some(target_file_path) => vec![Target::File(PathBuf::from(target_file_path))],
     }
 }
 
-fn tcp_read_with_headers(stream: &mut TcpStream) -> (u32, Vec<u8>) {
-    let mut initial_message = Vec::new();
-    while initial_message.len() < 4 {
-        initial_message.extend(one_tcp_read(stream));
+/// The index keeps the names unique even if two URLs sanitize to the same string
+fn output_file_name(index: usize, url: &str) -> String {
+    let sanitized: String = url.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '_' })
+        .take(MAX_FILE_NAME_URL_LENGTH)
+        .collect();
+    format!("{:04}_{}", index, sanitized)
+}
+
+/// Each kind of failure gets its own exit code so scripts can tell them apart
+fn exit_code(err: &ProxyError) -> i32 {
+    match err {
+        ProxyError::BadAddress(_) => 2,
+        ProxyError::Io(_) => 3,
+        ProxyError::UnexpectedEof => 4,
+        ProxyError::HandshakeRejected { .. } => 5,
+        ProxyError::ByeMismatch { .. } => 6,
+        ProxyError::FrameTooLarge { .. } => 7,
+        ProxyError::Timeout { .. } => 8,
+        ProxyError::ConnectFailed { .. } if err.class() == ErrorClass::Timeout => 8,
+        ProxyError::ResolveFailed { .. } => 9,
+        ProxyError::ConnectFailed { .. } => 10,
+        ProxyError::Tls(_) => 11,
+        ProxyError::Remote { .. } => 12,
+        ProxyError::UnexpectedMessage { .. } => 13,
+        ProxyError::InvalidRequest(_) => 14,
+        ProxyError::AuthFailed(_) => 15,
     }
-    parse_headers(initial_message)
 }
 
-fn one_tcp_read(stream: &mut TcpStream) -> Vec<u8> {
-    // TODO check if will block if not enough message was sent
-    let mut buffer = [0; MAX_BATCH_SIZE];
-    let count = stream.read(&mut buffer).expect("Failed reading from the stream");
-    if count == 0 {
-        panic!("Issue with the TCP read, got 0 bytes");
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::env;
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use rust_proxy_tcp_client::message::Handshake;
+    use rust_proxy_tcp_client::protocol::{FrameReader, MAX_CONTROL_FRAME_SIZE};
+    use rust_proxy_tcp_client::Message;
+    use super::*;
+
+    #[test]
+    fn reports_every_url_and_the_totals() {
+        let outcome = |url: &str, result| Outcome {
+            url: url.to_owned(),
+            path: PathBuf::from(format!("out/{}", url)),
+            result,
+            elapsed: Duration::from_millis(1500),
+        };
+        let outcomes = vec![
+            outcome("a", Ok(10)),
+            outcome("b", Err(ProxyError::Remote { code: 404, message: "Not found".to_owned() })),
+            outcome("c", Ok(20)),
+        ];
+        let mut report = Vec::new();
+        print_report(&mut report, &outcomes, Duration::from_secs(2)).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        let lines: Vec<_> = report.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("OK") && lines[0].contains(" 10 bytes") && lines[0].ends_with("a -> out/a"));
+        assert!(lines[1].starts_with("FAILED") && lines[1].ends_with("b: The proxy reported error 404: Not found"));
+        assert!(lines[2].contains(" 20 bytes") && lines[2].contains("1.50s"));
+        assert_eq!(lines[3], "2 succeeded, 1 failed, 30 bytes in 2.00s");
+    }
+
+    /// The URLs with the number of the connection that asked for them
+    type Served = Arc<Mutex<Vec<(usize, String)>>>;
+
+    /// A proxy answering every GET with the URL as the body, recording what each connection asked for
+    fn recording_proxy() -> (String, Served) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let served = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&served);
+        thread::spawn(move || {
+            for (connection, stream) in listener.incoming().enumerate() {
+                let served = Arc::clone(&recorded);
+                thread::spawn(move || {
+                    let mut stream = FrameReader::new(stream.unwrap());
+                    assert!(matches!(stream.read_message(MAX_CONTROL_FRAME_SIZE).unwrap(), Message::ConnectWith(_)));
+                    let accept = Handshake { version: 2, capabilities: Vec::new() };
+                    Message::AcceptWith(accept).send(stream.get_mut()).unwrap();
+                    loop {
+                        match stream.read_message(MAX_CONTROL_FRAME_SIZE).unwrap() {
+                            Message::Get { url } => {
+                                served.lock().unwrap().push((connection, url.clone()));
+                                Message::Data(url.into_bytes()).send(stream.get_mut()).unwrap();
+                            }
+                            Message::Bye => return Message::Bye.send(stream.get_mut()).unwrap(),
+                            got => panic!("Unexpected {}", got),
+                        }
+                    }
+                });
+            }
+        });
+        (address, served)
+    }
+
+    #[test]
+    fn every_url_is_fetched_exactly_once() {
+        let app = App::new("test").get_matches_from(vec!["test"]);
+        let urls: Vec<String> = (0..25).map(|index| format!("http://host/{}", index)).collect();
+        let total_bytes: usize = urls.iter().map(String::len).sum();
+        for parallel in [1, 4, 30] {
+            let (address, served) = recording_proxy();
+            let output_dir = env::temp_dir().join(format!("batch_{}_{}", parallel, process::id()));
+            fs::create_dir_all(&output_dir).unwrap();
+            let outcomes = fetch_in_parallel(&app, &address, &ClientConfig::default(), &urls, &output_dir, parallel);
+
+            let mut served = served.lock().unwrap().clone();
+            let connections: HashSet<_> = served.iter().map(|(connection, _)| *connection).collect();
+            assert!(connections.len() <= parallel, "{} sessions for {} in parallel", connections.len(), parallel);
+            // Sorted, so a URL served twice or never shows up
+            let mut served_urls: Vec<_> = served.drain(..).map(|(_, url)| url).collect();
+            let mut expected = urls.clone();
+            served_urls.sort();
+            expected.sort();
+            assert_eq!(served_urls, expected);
+
+            for (outcome, url) in outcomes.iter().zip(&urls) {
+                assert_eq!(&outcome.url, url);
+                assert_eq!(outcome.result.as_ref().unwrap(), &(url.len() as u64));
+                assert_eq!(outcome.path.parent(), Some(output_dir.as_path()));
+                assert_eq!(fs::read(&outcome.path).unwrap(), url.as_bytes());
+            }
+            assert_eq!(outcomes.len(), urls.len());
+            let mut report = Vec::new();
+            print_report(&mut report, &outcomes, Duration::from_secs(1)).unwrap();
+            let report = String::from_utf8(report).unwrap();
+            let totals = format!("25 succeeded, 0 failed, {} bytes in 1.00s", total_bytes);
+            assert_eq!(report.lines().last(), Some(totals.as_str()));
+            fs::remove_dir_all(output_dir).unwrap();
+        }
     }
-    buffer[..count].to_vec()
 }
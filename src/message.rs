@@ -0,0 +1,470 @@
+use std::borrow::Cow;
+use std::fmt;
+use std::str::FromStr;
+use std::io::{self, Write};
+use crate::auth::{from_hex, to_hex, AuthChallenge, AuthResponse};
+use crate::error::{ProxyError, Result};
+use crate::protocol::{
+    add_headers, generate_request_from_url, parse_headers, response_to_string, send_bytes,
+    ACCEPT_RESPONSE, AUTHENTICATED_RESPONSE, AUTH_PREFIX, BYE_MESSAGE, CHALLENGE_PREFIX, CONNECT_MESSAGE,
+    ERROR_PREFIX, METADATA_PREFIX, PING_MESSAGE, PONG_RESPONSE, PROTOCOL_VERSION, REQUEST_HEAD_PREFIX, REQUEST_PREFIX,
+};
+
+/// The messages of the protocol, shared by the client and the server.
+/// A frame carries the message payload after the 4 byte length header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    /// The legacy version 1 handshake
+    Connect,
+    Accept,
+    /// "Connect v<version> caps=<capabilities>", the client's newest version and what it understands
+    ConnectWith(Handshake),
+    /// "Accept v<version> caps=<capabilities>", the version and the capabilities the server chose
+    AcceptWith(Handshake),
+    /// The server requires authentication, sent after `AcceptWith`
+    Challenge(AuthChallenge),
+    Auth(AuthResponse),
+    /// The credentials were accepted, a rejection comes as an error frame
+    Authenticated,
+    Get { url: String },
+    /// Any other method or a request with headers, followed by a frame with the body
+    Request(RequestHead),
+    /// BYE is both the request to end the session and the answer to it
+    Bye,
+    Ping,
+    Pong,
+    /// Response body, never produced by decoding, as only the receiver
+    /// knows whether it's waiting for a body or for a control message
+    Data(Vec<u8>),
+    /// The proxy couldn't serve the request, sent instead of the body
+    Error { code: u32, message: String },
+    /// Status and headers of the upstream response, sent before the body
+    Metadata(Metadata),
+    /// Anything that isn't a known control message
+    Unknown(Vec<u8>),
+}
+
+impl Message {
+    /// Recognizes the control messages, anything else is `Unknown`
+    pub fn from_payload(payload: Vec<u8>) -> Message {
+        if payload == CONNECT_MESSAGE.as_bytes() {
+            return Message::Connect;
+        }
+        if payload == ACCEPT_RESPONSE.as_bytes() {
+            return Message::Accept;
+        }
+        if payload == BYE_MESSAGE.as_bytes() {
+            return Message::Bye;
+        }
+        if payload == PING_MESSAGE.as_bytes() {
+            return Message::Ping;
+        }
+        if payload == PONG_RESPONSE.as_bytes() {
+            return Message::Pong;
+        }
+        if payload == AUTHENTICATED_RESPONSE.as_bytes() {
+            return Message::Authenticated;
+        }
+        if let Some(challenge) = parse_challenge(&payload) {
+            return Message::Challenge(challenge);
+        }
+        if let Some(response) = parse_auth(&payload) {
+            return Message::Auth(response);
+        }
+        if let Some(handshake) = Handshake::parse(&payload, CONNECT_MESSAGE) {
+            return Message::ConnectWith(handshake);
+        }
+        if let Some(handshake) = Handshake::parse(&payload, ACCEPT_RESPONSE) {
+            return Message::AcceptWith(handshake);
+        }
+        if let Some((code, message)) = parse_error(&payload) {
+            return Message::Error { code, message };
+        }
+        if let Some(metadata) = Metadata::parse(&payload) {
+            return Message::Metadata(metadata);
+        }
+        if let Some(head) = RequestHead::parse(&payload) {
+            return Message::Request(head);
+        }
+        if let Some(url) = payload.strip_prefix(REQUEST_PREFIX.as_bytes()) {
+            if let Ok(url) = std::str::from_utf8(url) {
+                return Message::Get { url: url.to_owned() };
+            }
+        }
+        Message::Unknown(payload)
+    }
+
+    pub fn payload(&self) -> Cow<'_, [u8]> {
+        match self {
+            Message::Connect => Cow::Borrowed(CONNECT_MESSAGE.as_bytes()),
+            Message::Accept => Cow::Borrowed(ACCEPT_RESPONSE.as_bytes()),
+            Message::ConnectWith(handshake) => Cow::Owned(handshake.to_payload(CONNECT_MESSAGE)),
+            Message::AcceptWith(handshake) => Cow::Owned(handshake.to_payload(ACCEPT_RESPONSE)),
+            Message::Challenge(AuthChallenge::Token) => Cow::Owned(format!("{}token", CHALLENGE_PREFIX).into_bytes()),
+            Message::Challenge(AuthChallenge::Hmac { nonce }) =>
+                Cow::Owned(format!("{}hmac:{}", CHALLENGE_PREFIX, to_hex(nonce)).into_bytes()),
+            Message::Auth(AuthResponse::Token(token)) => Cow::Owned(format!("{}token:{}", AUTH_PREFIX, token).into_bytes()),
+            Message::Auth(AuthResponse::Hmac(mac)) => Cow::Owned(format!("{}hmac:{}", AUTH_PREFIX, to_hex(mac)).into_bytes()),
+            Message::Authenticated => Cow::Borrowed(AUTHENTICATED_RESPONSE.as_bytes()),
+            Message::Get { url } => Cow::Owned(generate_request_from_url(url).into_bytes()),
+            Message::Bye => Cow::Borrowed(BYE_MESSAGE.as_bytes()),
+            Message::Ping => Cow::Borrowed(PING_MESSAGE.as_bytes()),
+            Message::Pong => Cow::Borrowed(PONG_RESPONSE.as_bytes()),
+            Message::Error { code, message } =>
+                Cow::Owned(format!("{}{}:{}", ERROR_PREFIX, code, message).into_bytes()),
+            Message::Metadata(metadata) => Cow::Owned(metadata.to_payload()),
+            Message::Request(head) => Cow::Owned(head.to_payload()),
+            Message::Data(data) | Message::Unknown(data) => Cow::Borrowed(data),
+        }
+    }
+
+    /// The whole frame, header included
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        add_headers(&self.payload())
+    }
+
+    /// Decodes exactly one whole frame, header included
+    pub fn decode(frame: Vec<u8>) -> Result<Message> {
+        if frame.len() < 4 {
+            return Err(ProxyError::UnexpectedEof);
+        }
+        let (length, payload) = parse_headers(frame);
+        if payload.len() < length as usize {
+            return Err(ProxyError::UnexpectedEof);
+        }
+        if payload.len() > length as usize {
+            return Err(ProxyError::Io(io::Error::new(io::ErrorKind::InvalidData, "Bytes left after the frame")));
+        }
+        Ok(Message::from_payload(payload))
+    }
+
+    /// Sends the message as one frame
+    pub fn send<S: Write>(&self, socket: &mut S) -> Result<()> {
+        send_bytes(&self.payload(), socket)
+    }
+}
+
+/// Optional parts of the protocol, enabled only when both sides list them in the handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    /// The metadata frame before the response body
+    Metadata,
+    /// Error frames instead of the response body
+    Errors,
+    /// Requests with a method, headers and a body
+    Requests,
+    /// Response bodies split into chunks, ended by an empty one
+    Chunked,
+    /// The challenge/response step after Accept, offered by the clients that have credentials
+    Auth,
+    /// PING frames between the requests, answered with PONG
+    Ping,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 6] = [
+        Capability::Metadata,
+        Capability::Errors,
+        Capability::Requests,
+        Capability::Chunked,
+        Capability::Auth,
+        Capability::Ping,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Capability::Metadata => "meta",
+            Capability::Errors => "err",
+            Capability::Requests => "req",
+            Capability::Chunked => "chunked",
+            Capability::Auth => "auth",
+            Capability::Ping => "ping",
+        }
+    }
+}
+
+impl FromStr for Capability {
+    type Err = String;
+
+    fn from_str(name: &str) -> std::result::Result<Capability, String> {
+        Capability::ALL.into_iter()
+            .find(|capability| capability.name() == name)
+            .ok_or_else(|| format!("Unknown capability {:?}", name))
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// The protocol version and capabilities, offered by the client and chosen by the server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Handshake {
+    pub version: u32,
+    pub capabilities: Vec<Capability>,
+}
+
+impl Handshake {
+    /// The plain Connect/Accept exchange, where nothing is negotiated
+    pub fn legacy() -> Handshake {
+        Handshake { version: 1, capabilities: Vec::new() }
+    }
+
+    /// Everything this side supports, the clients that have credentials add `Auth`
+    pub fn offer() -> Handshake {
+        let capabilities = Capability::ALL.into_iter().filter(|capability| *capability != Capability::Auth).collect();
+        Handshake { version: PROTOCOL_VERSION, capabilities }
+    }
+
+    /// The server's answer to the client's offer, limited to what both sides support
+    pub fn choose(&self, supported: &[Capability]) -> Handshake {
+        Handshake {
+            version: self.version.min(PROTOCOL_VERSION),
+            capabilities: self.capabilities.iter().copied().filter(|capability| supported.contains(capability)).collect(),
+        }
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        self.version < 2
+    }
+
+    /// Version 1 negotiates nothing, so there the compatible extensions are assumed:
+    /// error and metadata frames are recognized by their prefix.
+    /// Anything this side would have to send, like requests other than a plain GET,
+    /// is only used when negotiated, version 1 servers would drop the connection.
+    pub fn supports(&self, capability: Capability) -> bool {
+        match capability {
+            Capability::Requests | Capability::Chunked | Capability::Auth | Capability::Ping =>
+                self.capabilities.contains(&capability),
+            _ => self.is_legacy() || self.capabilities.contains(&capability),
+        }
+    }
+
+    /// "<keyword> v<version> caps=<comma separated capabilities>"
+    fn to_payload(&self, keyword: &str) -> Vec<u8> {
+        let capabilities: Vec<_> = self.capabilities.iter().map(|capability| capability.name()).collect();
+        format!("{} v{} caps={}", keyword, self.version, capabilities.join(",")).into_bytes()
+    }
+
+    /// Unknown capabilities and any further fields are skipped,
+    /// so newer peers can add them without breaking this side
+    fn parse(payload: &[u8], keyword: &str) -> Option<Handshake> {
+        let mut fields = std::str::from_utf8(payload).ok()?.split(' ');
+        if fields.next()? != keyword {
+            return None;
+        }
+        let version = fields.next()?.strip_prefix('v')?.parse().ok()?;
+        let capabilities = fields
+            .find_map(|field| field.strip_prefix("caps="))
+            .map(|names| names.split(',').filter_map(|name| name.parse().ok()).collect())
+            .unwrap_or_default();
+        Some(Handshake { version, capabilities })
+    }
+}
+
+/// Method, URL and headers of a request, the body goes in the next frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestHead {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl RequestHead {
+    /// "REQ:<method> <url>" followed by one "Name: Value" line per header
+    fn to_payload(&self) -> Vec<u8> {
+        let mut payload = format!("{}{} {}", REQUEST_HEAD_PREFIX, self.method, self.url);
+        for (name, value) in &self.headers {
+            payload.push_str(&format!("\n{}: {}", name, value));
+        }
+        payload.into_bytes()
+    }
+
+    fn parse(payload: &[u8]) -> Option<RequestHead> {
+        let rest = std::str::from_utf8(payload.strip_prefix(REQUEST_HEAD_PREFIX.as_bytes())?).ok()?;
+        let mut lines = rest.split('\n');
+        let (method, url) = lines.next()?.split_once(' ')?;
+        let headers = lines
+            .map(|line| line.split_once(':').map(|(name, value)| (name.to_owned(), value.trim_start().to_owned())))
+            .collect::<Option<_>>()?;
+        Some(RequestHead { method: method.to_owned(), url: url.to_owned(), headers })
+    }
+}
+
+/// Status code and headers of the response the proxy got from upstream
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metadata {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+}
+
+impl Metadata {
+    /// The first header with the name, ignoring the case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn content_length(&self) -> Option<u64> {
+        self.header("Content-Length")?.trim().parse().ok()
+    }
+
+    /// "META:<status>" followed by one "Name: Value" line per header
+    fn to_payload(&self) -> Vec<u8> {
+        let mut payload = format!("{}{}", METADATA_PREFIX, self.status);
+        for (name, value) in &self.headers {
+            payload.push_str(&format!("\n{}: {}", name, value));
+        }
+        payload.into_bytes()
+    }
+
+    /// Anything malformed isn't treated as a metadata frame
+    pub(crate) fn parse(payload: &[u8]) -> Option<Metadata> {
+        let rest = std::str::from_utf8(payload.strip_prefix(METADATA_PREFIX.as_bytes())?).ok()?;
+        let mut lines = rest.split('\n');
+        let status = lines.next()?.parse().ok()?;
+        let headers = lines
+            .map(|line| line.split_once(':').map(|(name, value)| (name.to_owned(), value.trim_start().to_owned())))
+            .collect::<Option<_>>()?;
+        Some(Metadata { status, headers })
+    }
+
+    /// The headers in the HTTP form, with a "Status:" line first as in CGI
+    pub fn write_to<W: Write + ?Sized>(&self, out: &mut W) -> io::Result<()> {
+        write!(out, "Status: {}\r\n", self.status)?;
+        for (name, value) in &self.headers {
+            write!(out, "{}: {}\r\n", name, value)?;
+        }
+        write!(out, "\r\n")
+    }
+}
+
+/// "CHALLENGE:token" or "CHALLENGE:hmac:<hex nonce>"
+fn parse_challenge(payload: &[u8]) -> Option<AuthChallenge> {
+    let rest = std::str::from_utf8(payload.strip_prefix(CHALLENGE_PREFIX.as_bytes())?).ok()?;
+    match rest.split_once(':') {
+        None if rest == "token" => Some(AuthChallenge::Token),
+        Some(("hmac", nonce)) => Some(AuthChallenge::Hmac { nonce: from_hex(nonce)? }),
+        _ => None,
+    }
+}
+
+/// "AUTH:token:<token>" or "AUTH:hmac:<hex mac>"
+fn parse_auth(payload: &[u8]) -> Option<AuthResponse> {
+    let rest = std::str::from_utf8(payload.strip_prefix(AUTH_PREFIX.as_bytes())?).ok()?;
+    match rest.split_once(':')? {
+        ("token", token) => Some(AuthResponse::Token(token.to_owned())),
+        ("hmac", mac) => Some(AuthResponse::Hmac(from_hex(mac)?)),
+        _ => None,
+    }
+}
+
+/// "ERR:<code>:<message>", anything malformed isn't treated as an error frame
+/// The message may be cut off from an upstream error, so broken UTF-8 in it is replaced
+fn parse_error(payload: &[u8]) -> Option<(u32, String)> {
+    let rest = payload.strip_prefix(ERROR_PREFIX.as_bytes())?;
+    let separator = rest.iter().position(|&byte| byte == b':')?;
+    let code = std::str::from_utf8(&rest[..separator]).ok()?.parse().ok()?;
+    Some((code, String::from_utf8_lossy(&rest[separator + 1..]).into_owned()))
+}
+
+/// The error reported by the proxy, if the payload is an error frame
+pub fn remote_error(payload: &[u8]) -> Option<ProxyError> {
+    parse_error(payload).map(|(code, message)| ProxyError::Remote { code, message })
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Message::Connect => write!(f, "Connect"),
+            Message::Accept => write!(f, "Accept"),
+            Message::ConnectWith(handshake) => write!(f, "Connect v{}", handshake.version),
+            Message::AcceptWith(handshake) => write!(f, "Accept v{}", handshake.version),
+            Message::Challenge(AuthChallenge::Token) => write!(f, "token challenge"),
+            Message::Challenge(AuthChallenge::Hmac { .. }) => write!(f, "HMAC challenge"),
+            Message::Auth(AuthResponse::Token(_)) => write!(f, "token"),
+            Message::Auth(AuthResponse::Hmac(_)) => write!(f, "HMAC response"),
+            Message::Authenticated => write!(f, "Authenticated"),
+            Message::Get { url } => write!(f, "GET {}", url),
+            Message::Request(head) => write!(f, "{} {}", head.method, head.url),
+            Message::Bye => write!(f, "BYE"),
+            Message::Ping => write!(f, "PING"),
+            Message::Pong => write!(f, "PONG"),
+            Message::Error { code, message } => write!(f, "error {}: {}", code, message),
+            Message::Metadata(metadata) =>
+                write!(f, "metadata, status {} with {} headers", metadata.status, metadata.headers.len()),
+            Message::Data(data) => write!(f, "{} bytes of data", data.len()),
+            Message::Unknown(payload) =>
+                write!(f, "unknown message {:?} ({} bytes)", response_to_string(payload.clone()), payload.len()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn control_messages_survive_encoding() {
+        let messages = [
+            Message::Connect,
+            Message::Accept,
+            Message::ConnectWith(Handshake::offer()),
+            Message::Challenge(AuthChallenge::Token),
+            Message::Challenge(AuthChallenge::Hmac { nonce: vec![0, 1, 254, 255] }),
+            Message::Auth(AuthResponse::Token("with:colons".to_owned())),
+            Message::Auth(AuthResponse::Hmac(vec![0xab; 32])),
+            Message::Authenticated,
+            Message::AcceptWith(Handshake { version: 2, capabilities: Vec::new() }),
+            Message::Get { url: "http://example.com/a?b=c".to_owned() },
+            Message::Bye,
+            Message::Ping,
+            Message::Pong,
+            Message::Error { code: 404, message: "Not: found".to_owned() },
+            Message::Metadata(Metadata {
+                status: 200,
+                headers: vec![("Content-Type".to_owned(), "text/html".to_owned()), ("X-Empty".to_owned(), String::new())],
+            }),
+            Message::Metadata(Metadata { status: 204, headers: Vec::new() }),
+            Message::Request(RequestHead {
+                method: "POST".to_owned(),
+                url: "http://example.com/a b".to_owned(),
+                headers: vec![("Content-Type".to_owned(), "application/json".to_owned())],
+            }),
+            Message::Unknown(b"Nope".to_vec()),
+        ];
+        for message in messages {
+            assert_eq!(Message::decode(message.encode().unwrap()).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn decodes_the_legacy_strings() {
+        assert_eq!(Message::from_payload(b"GET:http://x/".to_vec()), Message::Get { url: "http://x/".to_owned() });
+        assert_eq!(Message::from_payload(b"Accept".to_vec()), Message::Accept);
+        assert_eq!(Message::from_payload(b"accept".to_vec()), Message::Unknown(b"accept".to_vec()));
+    }
+
+    #[test]
+    fn skips_what_it_doesnt_know_in_the_handshake() {
+        let connect = Message::from_payload(b"Connect v3 caps=meta,zstd,err extra=1".to_vec());
+        let offer = Handshake { version: 3, capabilities: vec![Capability::Metadata, Capability::Errors] };
+        assert_eq!(connect, Message::ConnectWith(offer.clone()));
+        let chosen = offer.choose(&[Capability::Errors, Capability::Requests]);
+        assert_eq!(chosen, Handshake { version: PROTOCOL_VERSION, capabilities: vec![Capability::Errors] });
+        assert!(!chosen.supports(Capability::Metadata));
+        assert!(Handshake::legacy().supports(Capability::Metadata));
+        assert!(!Handshake::legacy().supports(Capability::Requests));
+        assert_eq!(Message::from_payload(b"Connectv2".to_vec()), Message::Unknown(b"Connectv2".to_vec()));
+    }
+
+    #[test]
+    fn refuses_incomplete_frames() {
+        let frame = Message::Accept.encode().unwrap();
+        assert!(matches!(Message::decode(frame[..frame.len() - 1].to_vec()), Err(ProxyError::UnexpectedEof)));
+        assert!(matches!(Message::decode(frame[..2].to_vec()), Err(ProxyError::UnexpectedEof)));
+    }
+}
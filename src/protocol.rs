@@ -0,0 +1,615 @@
+use std::cmp::min;
+use std::mem;
+use std::io::{self, Read, Write};
+use std::ops::Add;
+use crate::error::{ProxyError, Result};
+use crate::message::{remote_error, Capability, Handshake, Message, Metadata};
+
+pub const CONNECT_MESSAGE: &str = "Connect";
+/// The newest protocol version this side speaks, the bare Connect/Accept exchange is version 1
+pub const PROTOCOL_VERSION: u32 = 2;
+pub const ACCEPT_RESPONSE: &str = "Accept";
+pub const REQUEST_PREFIX: &str = "GET:";
+/// Requests other than a plain GET, as "REQ:<method> <url>" followed by "Name: Value" lines,
+/// the next frame carries the request body
+pub const REQUEST_HEAD_PREFIX: &str = "REQ:";
+pub const BYE_MESSAGE: &str = "BYE";
+pub const BYE_RESPONSE: &str = "BYE";
+/// Heartbeat, answered with PONG as soon as it's read
+pub const PING_MESSAGE: &str = "PING";
+pub const PONG_RESPONSE: &str = "PONG";
+/// Sent by the servers requiring authentication after Accept, as "CHALLENGE:token" or "CHALLENGE:hmac:<nonce>"
+pub const CHALLENGE_PREFIX: &str = "CHALLENGE:";
+/// The answer to the challenge, as "AUTH:token:<token>" or "AUTH:hmac:<mac>"
+pub const AUTH_PREFIX: &str = "AUTH:";
+pub const AUTHENTICATED_RESPONSE: &str = "Authenticated";
+/// Sent instead of the response body as "ERR:<code>:<message>"
+pub const ERROR_PREFIX: &str = "ERR:";
+/// Optionally sent before the response body as "META:<status>" followed by "Name: Value" lines
+pub const METADATA_PREFIX: &str = "META:";
+pub const MAX_BATCH_SIZE: usize = 500;
+/// Limit for the frames that only carry a short message, like Accept and BYE
+pub const MAX_CONTROL_FRAME_SIZE: u64 = 1024;
+/// Limit for the metadata frame, upstream headers can be long
+pub const MAX_METADATA_SIZE: u64 = 64 * 1024;
+/// Limit for the error frames, the message can carry a long upstream error
+pub const MAX_ERROR_FRAME_SIZE: u64 = 64 * 1024;
+/// Size of the chunks a streamed body is split into
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+pub fn generate_request_from_url(url: &str) -> String {
+    String::from(REQUEST_PREFIX)
+        .add(url)
+}
+
+pub fn response_to_string(content: Vec<u8>) -> String {
+    String::from_utf8_lossy(content.as_slice()).to_string()
+}
+
+pub fn send_message<S: Write>(message: String, socket: &mut S) -> Result<()> {
+    send_bytes(message.as_bytes(), socket)
+}
+
+/// Sends an arbitrary payload as one frame, used for the response bodies
+pub fn send_bytes<S: Write>(message: &[u8], socket: &mut S) -> Result<()> {
+    let mut index = 0;
+    let buf = add_headers(message)?;
+    while index < buf.len() {
+        let count = socket.write(&buf[index..])?;
+        if count == 0 {
+            return Err(ProxyError::UnexpectedEof);
+        }
+        index += count;
+    }
+    Ok(())
+}
+
+/// Sends `length` bytes read from `body` as one frame, without loading them into memory
+pub fn send_stream<R: Read, S: Write>(length: u64, body: &mut R, socket: &mut S) -> Result<()> {
+    if length > u32::MAX as u64 {
+        return Err(ProxyError::FrameTooLarge { length, max: u32::MAX as u64 });
+    }
+    socket.write_all(&(length as u32).to_be_bytes())?;
+    let count = io::copy(&mut body.take(length), socket)?;
+    if count < length {
+        return Err(ProxyError::Io(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("The request body ended after {} of {} bytes", count, length),
+        )));
+    }
+    Ok(())
+}
+
+/// Sends everything read from `body` as chunks, followed by the empty chunk ending it.
+/// Returns the number of body bytes sent.
+pub fn send_chunks<R: Read, S: Write>(body: &mut R, socket: &mut S) -> Result<u64> {
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut sent = 0;
+    loop {
+        let count = match body.read(&mut buffer) {
+            Ok(count) => count,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        };
+        send_bytes(&buffer[..count], socket)?;
+        if count == 0 {
+            return Ok(sent);
+        }
+        sent += count as u64;
+    }
+}
+
+pub fn add_headers(message: &[u8]) -> Result<Vec<u8>> {
+    let length = message.len();
+    if length > u32::MAX as usize {
+        return Err(ProxyError::FrameTooLarge { length: length as u64, max: u32::MAX as u64 });
+    }
+    let length_bytes = (length as u32).to_be_bytes();
+    let mut new_message = Vec::new();
+    new_message.extend(length_bytes);
+    new_message.extend(message);
+    Ok(new_message)
+}
+
+pub fn parse_headers(message: Vec<u8>) -> (u32, Vec<u8>) {
+    (u32::from_be_bytes([message[0], message[1], message[2], message[3]]),
+     message[4..].to_vec())
+}
+
+/// What came back for a request, the body itself goes to the writer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    /// Only sent by the proxies that forward the upstream status and headers
+    pub metadata: Option<Metadata>,
+    pub body_length: u64,
+}
+
+/// A frame answering a request, when it's the body
+/// the reader is left right before the body bytes
+#[derive(Debug)]
+pub enum ResponseFrame {
+    Metadata(Metadata),
+    Body { length: u64 },
+}
+
+/// Reads the frames of the custom protocol:
+/// first 4 bytes are responsible for showing the length of the message.
+/// Bytes read past the end of a frame are kept for the next one,
+/// so frames coalesced into one TCP segment aren't lost.
+pub struct FrameReader<S> {
+    stream: S,
+    leftover: Vec<u8>,
+}
+
+impl<S: Read> FrameReader<S> {
+    pub fn new(stream: S) -> FrameReader<S> {
+        FrameReader { stream, leftover: Vec::new() }
+    }
+
+    /// For writing to the stream, reading directly would skip the leftover bytes
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Reads a control message, anything unexpected comes back as `Message::Unknown`
+    pub fn read_message(&mut self, max_length: u64) -> Result<Message> {
+        Ok(Message::from_payload(self.load_message(max_length)?))
+    }
+
+    /// Frames announcing more than `max_length` bytes are refused before reading the body
+    pub fn load_message(&mut self, max_length: u64) -> Result<Vec<u8>> {
+        let mut overall_message = Vec::new();
+        self.copy_message(&mut overall_message, max_length)?;
+        Ok(overall_message)
+    }
+
+    /// Same as `load_message`, but the body is written into `out` as it arrives,
+    /// so memory use doesn't depend on the size of the response.
+    /// Returns the number of body bytes written.
+    pub fn copy_message<W: Write>(&mut self, out: &mut W, max_length: u64) -> Result<u64> {
+        let overall_length = self.read_header(max_length)?;
+        self.copy_body(out, overall_length)
+    }
+
+    /// Reads a whole response into `out`: an error frame from the proxy is returned
+    /// as `ProxyError::Remote` without writing anything into `out`, the metadata
+    /// is passed to `on_metadata` before the body, and a chunked body is followed
+    /// until its last chunk. Whatever `on_metadata` writes ends up ahead of the body.
+    /// A body announcing a Content-Length over `max_length` in the metadata is refused
+    /// before reading it, chunked bodies without one are cut off once they pass the limit.
+    pub fn copy_response<W, F>(&mut self, out: &mut W, max_length: u64, handshake: &Handshake, mut on_metadata: F) -> Result<Response>
+    where
+        W: Write,
+        F: FnMut(&Metadata, &mut dyn Write) -> io::Result<()>,
+    {
+        let (metadata, length) = match self.read_response_frame(max_length, handshake)? {
+            ResponseFrame::Metadata(metadata) => {
+                on_metadata(&metadata, out)?;
+                let length = self.read_body_frame(max_length, handshake)?;
+                // Only when there is a body, the Content-Length of a HEAD response describes none
+                match metadata.content_length() {
+                    Some(content_length) if content_length > max_length && length > 0 =>
+                        return Err(ProxyError::FrameTooLarge { length: content_length, max: max_length }),
+                    _ => (Some(metadata), length),
+                }
+            }
+            ResponseFrame::Body { length } => (None, length),
+        };
+        let body_length = io::copy(&mut self.body_reader(length, max_length, handshake), out)?;
+        Ok(Response { metadata, body_length })
+    }
+
+    /// Streams the body whose first frame was announced with `first_length`.
+    /// With the chunked capability more chunks follow until an empty one,
+    /// and `max_length` limits their total size.
+    pub fn body_reader(&mut self, first_length: u64, max_length: u64, handshake: &Handshake) -> BodyReader<'_, S> {
+        let chunked = handshake.supports(Capability::Chunked);
+        BodyReader {
+            reader: self,
+            chunked,
+            remaining: first_length,
+            total: first_length,
+            max_length,
+            finished: chunked && first_length == 0,
+        }
+    }
+
+    /// Reads the next frame answering a request. Error frames are returned
+    /// as `ProxyError::Remote`, for the body only the length is read,
+    /// the body itself has to be taken with `copy_body`.
+    /// Only the frames the handshake enabled are recognized, and bodies that
+    /// only look like them, being too big or failing to parse, are still bodies.
+    pub fn read_response_frame(&mut self, max_length: u64, handshake: &Handshake) -> Result<ResponseFrame> {
+        let overall_length = self.read_length()?;
+        let errors = handshake.supports(Capability::Errors);
+        let metadata = handshake.supports(Capability::Metadata);
+        if let Some(payload) = self.take_prefixed(overall_length, ERROR_PREFIX, MAX_ERROR_FRAME_SIZE, errors)? {
+            if let Some(err) = remote_error(&payload) {
+                return Err(err);
+            }
+            self.unread(payload);
+        } else if let Some(payload) = self.take_prefixed(overall_length, METADATA_PREFIX, MAX_METADATA_SIZE, metadata)? {
+            if let Some(metadata) = Metadata::parse(&payload) {
+                return Ok(ResponseFrame::Metadata(metadata));
+            }
+            self.unread(payload);
+        }
+        if overall_length > max_length {
+            return Err(ProxyError::FrameTooLarge { length: overall_length, max: max_length });
+        }
+        Ok(ResponseFrame::Body { length: overall_length })
+    }
+
+    /// The frame after the metadata. From version 2 on it's always the body,
+    /// version 1 servers can still send an error there.
+    pub fn read_body_frame(&mut self, max_length: u64, handshake: &Handshake) -> Result<u64> {
+        if !handshake.is_legacy() {
+            return self.read_header(max_length);
+        }
+        match self.read_response_frame(max_length, handshake)? {
+            ResponseFrame::Body { length } => Ok(length),
+            ResponseFrame::Metadata(metadata) => Err(ProxyError::UnexpectedMessage { got: Message::Metadata(metadata) }),
+        }
+    }
+
+    /// Copies the body of the frame whose length was already read
+    pub fn copy_body<W: Write>(&mut self, out: &mut W, overall_length: u64) -> Result<u64> {
+        let initial_count = min(self.leftover.len() as u64, overall_length) as usize;
+        out.write_all(&self.leftover[..initial_count])?;
+        self.leftover.drain(..initial_count);
+        let mut written = initial_count as u64;
+        // Reads never go past the end of the body, so the leftover stays empty from here
+        let mut buffer = [0; MAX_BATCH_SIZE];
+        while written < overall_length {
+            let wanted = min(overall_length - written, MAX_BATCH_SIZE as u64) as usize;
+            let count = self.stream.read(&mut buffer[..wanted])?;
+            if count == 0 {
+                return Err(ProxyError::UnexpectedEof);
+            }
+            out.write_all(&buffer[..count])?;
+            written += count as u64;
+        }
+        Ok(written)
+    }
+
+    fn read_header(&mut self, max_length: u64) -> Result<u64> {
+        let length = self.read_length()?;
+        if length > max_length {
+            return Err(ProxyError::FrameTooLarge { length, max: max_length });
+        }
+        Ok(length)
+    }
+
+    fn read_length(&mut self) -> Result<u64> {
+        self.fill(4)?;
+        let (length, rest) = parse_headers(mem::take(&mut self.leftover));
+        self.leftover = rest;
+        Ok(length as u64)
+    }
+
+    /// Loads the payload of the current frame if it starts with the prefix
+    /// and isn't longer than `max_length`, otherwise nothing is consumed
+    fn take_prefixed(&mut self, overall_length: u64, prefix: &str, max_length: u64, enabled: bool) -> Result<Option<Vec<u8>>> {
+        if !enabled || overall_length < prefix.len() as u64 || overall_length > max_length {
+            return Ok(None);
+        }
+        self.fill(prefix.len())?;
+        if !self.leftover.starts_with(prefix.as_bytes()) {
+            return Ok(None);
+        }
+        let mut payload = Vec::new();
+        self.copy_body(&mut payload, overall_length)?;
+        Ok(Some(payload))
+    }
+
+    /// Puts the payload back, so it's read again as the body
+    fn unread(&mut self, payload: Vec<u8>) {
+        self.leftover.splice(0..0, payload);
+    }
+
+    /// Takes the leftover first, reads from the stream only once it's empty
+    fn read_some(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.leftover.is_empty() {
+            return Ok(self.stream.read(buf)?);
+        }
+        let count = min(buf.len(), self.leftover.len());
+        buf[..count].copy_from_slice(&self.leftover[..count]);
+        self.leftover.drain(..count);
+        Ok(count)
+    }
+
+    /// Reads until at least `count` bytes are buffered
+    fn fill(&mut self, count: usize) -> Result<()> {
+        while self.leftover.len() < count {
+            let chunk = one_tcp_read(&mut self.stream)?;
+            self.leftover.extend(chunk);
+        }
+        Ok(())
+    }
+}
+
+/// The body of a response as a stream, whether it came in one frame or in chunks.
+/// Protocol errors are wrapped into `io::Error`, converting it back into
+/// `ProxyError` restores them.
+pub struct BodyReader<'a, S> {
+    reader: &'a mut FrameReader<S>,
+    chunked: bool,
+    /// Left in the current frame
+    remaining: u64,
+    /// Announced so far, for the size limit
+    total: u64,
+    max_length: u64,
+    finished: bool,
+}
+
+impl<S: Read> BodyReader<'_, S> {
+    fn read_body(&mut self, buf: &mut [u8]) -> Result<usize> {
+        while self.remaining == 0 {
+            if self.finished || !self.chunked {
+                self.finished = true;
+                return Ok(0);
+            }
+            let length = self.reader.read_length()?;
+            if length == 0 {
+                self.finished = true;
+                return Ok(0);
+            }
+            self.total += length;
+            if self.total > self.max_length {
+                return Err(ProxyError::FrameTooLarge { length: self.total, max: self.max_length });
+            }
+            self.remaining = length;
+        }
+        let wanted = min(self.remaining, buf.len() as u64) as usize;
+        let count = self.reader.read_some(&mut buf[..wanted])?;
+        if count == 0 && wanted > 0 {
+            return Err(ProxyError::UnexpectedEof);
+        }
+        self.remaining -= count as u64;
+        Ok(count)
+    }
+}
+
+impl<S: Read> Read for BodyReader<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_body(buf).map_err(ProxyError::into_io)
+    }
+}
+
+fn one_tcp_read<S: Read>(stream: &mut S) -> Result<Vec<u8>> {
+    let mut buffer = [0; MAX_BATCH_SIZE];
+    let count = stream.read(&mut buffer)?;
+    if count == 0 {
+        return Err(ProxyError::UnexpectedEof);
+    }
+    Ok(buffer[..count].to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::io::{self, Read};
+    use super::*;
+
+    /// Hands out the given chunks one read at a time, like TCP segments
+    struct ChunkedStream {
+        chunks: VecDeque<Vec<u8>>,
+    }
+
+    impl ChunkedStream {
+        fn new(chunks: Vec<Vec<u8>>) -> ChunkedStream {
+            ChunkedStream { chunks: chunks.into_iter().filter(|chunk| !chunk.is_empty()).collect() }
+        }
+    }
+
+    impl Read for ChunkedStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let chunk = match self.chunks.front_mut() {
+                Some(chunk) => chunk,
+                None => return Ok(0),
+            };
+            let count = min(buf.len(), chunk.len());
+            buf[..count].copy_from_slice(&chunk[..count]);
+            chunk.drain(..count);
+            if chunk.is_empty() {
+                self.chunks.pop_front();
+            }
+            Ok(count)
+        }
+    }
+
+    fn frames(messages: &[&[u8]]) -> Vec<u8> {
+        messages.iter().flat_map(|message| add_headers(message).unwrap()).collect()
+    }
+
+    fn read_all(chunks: Vec<Vec<u8>>, count: usize) -> Vec<Vec<u8>> {
+        let mut reader = FrameReader::new(ChunkedStream::new(chunks));
+        (0..count).map(|_| reader.load_message(u64::MAX).unwrap()).collect()
+    }
+
+    #[test]
+    fn keeps_frames_coalesced_into_one_read() {
+        let body = b"body of the response".to_vec();
+        let data = frames(&[ACCEPT_RESPONSE.as_bytes(), &body, BYE_RESPONSE.as_bytes()]);
+        let messages = read_all(vec![data], 3);
+        assert_eq!(messages, vec![ACCEPT_RESPONSE.as_bytes().to_vec(), body, BYE_RESPONSE.as_bytes().to_vec()]);
+    }
+
+    #[test]
+    fn decodes_frames_split_at_every_offset() {
+        let messages: Vec<&[u8]> = vec![b"Accept", b"", b"some body", b"BYE"];
+        let data = frames(&messages);
+        for offset in 0..=data.len() {
+            let chunks = vec![data[..offset].to_vec(), data[offset..].to_vec()];
+            assert_eq!(read_all(chunks, messages.len()), messages, "split at {}", offset);
+        }
+    }
+
+    #[test]
+    fn decodes_frames_split_at_every_pair_of_offsets() {
+        let messages: Vec<&[u8]> = vec![b"Accept", b"some body", b"BYE"];
+        let data = frames(&messages);
+        for first in 0..=data.len() {
+            for second in first..=data.len() {
+                let chunks = vec![data[..first].to_vec(), data[first..second].to_vec(), data[second..].to_vec()];
+                assert_eq!(read_all(chunks, messages.len()), messages, "split at {} and {}", first, second);
+            }
+        }
+    }
+
+    #[test]
+    fn decodes_frames_arriving_byte_by_byte() {
+        let messages: Vec<&[u8]> = vec![b"Accept", b"some body", b"BYE"];
+        let chunks = frames(&messages).into_iter().map(|byte| vec![byte]).collect();
+        assert_eq!(read_all(chunks, messages.len()), messages);
+    }
+
+    #[test]
+    fn keeps_the_next_frame_after_a_body_longer_than_a_batch() {
+        let body: Vec<u8> = (0..MAX_BATCH_SIZE * 3 + 7).map(|index| index as u8).collect();
+        let data = frames(&[&body, BYE_RESPONSE.as_bytes()]);
+        for offset in [1, 4, 5, MAX_BATCH_SIZE, data.len() - 5, data.len() - 1] {
+            let chunks = vec![data[..offset].to_vec(), data[offset..].to_vec()];
+            assert_eq!(read_all(chunks, 2), vec![body.clone(), BYE_RESPONSE.as_bytes().to_vec()]);
+        }
+    }
+
+    #[test]
+    fn reports_eof_in_the_middle_of_a_frame() {
+        let data = frames(&[b"some body"]);
+        for offset in 0..data.len() {
+            let mut reader = FrameReader::new(ChunkedStream::new(vec![data[..offset].to_vec()]));
+            assert!(matches!(reader.load_message(u64::MAX), Err(ProxyError::UnexpectedEof)));
+        }
+    }
+
+    #[test]
+    fn refuses_frames_over_the_limit_before_reading_the_body() {
+        let data = add_headers(&[0; 100]).unwrap();
+        let mut reader = FrameReader::new(ChunkedStream::new(vec![data[..4].to_vec()]));
+        assert!(matches!(
+            reader.load_message(99),
+            Err(ProxyError::FrameTooLarge { length: 100, max: 99 }),
+        ));
+    }
+
+    #[test]
+    fn reports_error_frames_without_writing_them() {
+        let error = Message::Error { code: 404, message: "Not found".to_owned() };
+        let data = frames(&[&error.payload(), BYE_RESPONSE.as_bytes()]);
+        let mut reader = FrameReader::new(ChunkedStream::new(vec![data]));
+        let mut out = Vec::new();
+        assert!(matches!(
+            reader.copy_response(&mut out, u64::MAX, &Handshake::legacy(), |_, _| Ok(())),
+            Err(ProxyError::Remote { code: 404, ref message }) if message == "Not found",
+        ));
+        assert!(out.is_empty());
+        assert_eq!(reader.read_message(MAX_CONTROL_FRAME_SIZE).unwrap(), Message::Bye);
+    }
+
+    #[test]
+    fn reports_error_frames_with_long_messages() {
+        let message = "Bad gateway: ".to_owned() + &"upstream said no. ".repeat(200);
+        let error = Message::Error { code: 502, message: message.clone() }.payload().to_vec();
+        assert!(error.len() as u64 > MAX_CONTROL_FRAME_SIZE);
+        let mut reader = FrameReader::new(ChunkedStream::new(vec![frames(&[&error])]));
+        let mut out = Vec::new();
+        match reader.copy_response(&mut out, 100, &Handshake::legacy(), |_, _| Ok(())) {
+            Err(ProxyError::Remote { code: 502, message: got }) => assert_eq!(got, message),
+            other => panic!("Expected the remote error, got {:?}", other),
+        }
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn writes_bodies_that_only_look_like_errors() {
+        let body = b"ERR: is how this page starts".to_vec();
+        let mut reader = FrameReader::new(ChunkedStream::new(vec![frames(&[&body])]));
+        let mut out = Vec::new();
+        reader.copy_response(&mut out, u64::MAX, &Handshake::legacy(), |_, _| Ok(())).unwrap();
+        assert_eq!(out, body);
+    }
+
+    #[test]
+    fn reads_the_metadata_before_the_body() {
+        let metadata = Metadata { status: 200, headers: vec![("Content-Length".to_owned(), "4".to_owned())] };
+        let data = frames(&[&Message::Metadata(metadata.clone()).payload(), b"body", b"META: the next body"]);
+        let mut reader = FrameReader::new(ChunkedStream::new(vec![data]));
+        let mut out = Vec::new();
+        let response = reader.copy_response(&mut out, 4, &Handshake::legacy(), |got, out| {
+            assert_eq!(got, &metadata);
+            out.write_all(b"head ")
+        }).unwrap();
+        assert_eq!(response, Response { metadata: Some(metadata), body_length: 4 });
+        assert_eq!(out, b"head body");
+        out.clear();
+        reader.copy_response(&mut out, u64::MAX, &Handshake::legacy(), |_, _| Ok(())).unwrap();
+        assert_eq!(out, b"META: the next body");
+    }
+
+    #[test]
+    fn recognizes_only_the_negotiated_frames() {
+        let error = Message::Error { code: 404, message: "Not found".to_owned() }.payload().to_vec();
+        let metadata = Message::Metadata(Metadata { status: 200, headers: Vec::new() }).payload().to_vec();
+        let data = frames(&[&error, &metadata, &error]);
+        let mut reader = FrameReader::new(ChunkedStream::new(vec![data]));
+        let handshake = Handshake { version: 2, capabilities: vec![Capability::Metadata] };
+        let mut out = Vec::new();
+        reader.copy_response(&mut out, u64::MAX, &handshake, |_, _| Ok(())).unwrap();
+        assert_eq!(out, error);
+        out.clear();
+        // After the metadata comes the body, whatever it looks like
+        reader.copy_response(&mut out, u64::MAX, &handshake, |_, _| Ok(())).unwrap();
+        assert_eq!(out, error);
+    }
+
+    #[test]
+    fn streams_chunked_bodies() {
+        let handshake = Handshake { version: 2, capabilities: vec![Capability::Chunked] };
+        let data = frames(&[b"first ", b"second", b"", b"", BYE_RESPONSE.as_bytes()]);
+        for split in 0..data.len() {
+            let chunks = vec![data[..split].to_vec(), data[split..].to_vec()];
+            let mut reader = FrameReader::new(ChunkedStream::new(chunks));
+            let mut out = Vec::new();
+            assert_eq!(reader.copy_response(&mut out, u64::MAX, &handshake, |_, _| Ok(())).unwrap().body_length, 12);
+            assert_eq!(out, b"first second");
+            out.clear();
+            assert_eq!(reader.copy_response(&mut out, u64::MAX, &handshake, |_, _| Ok(())).unwrap().body_length, 0);
+            assert_eq!(reader.read_message(MAX_CONTROL_FRAME_SIZE).unwrap(), Message::Bye);
+        }
+        let mut reader = FrameReader::new(ChunkedStream::new(vec![frames(&[b"first ", b"second", b""])]));
+        assert!(matches!(
+            reader.copy_response(&mut Vec::new(), 10, &handshake, |_, _| Ok(())),
+            Err(ProxyError::FrameTooLarge { length: 12, max: 10 }),
+        ));
+    }
+
+    #[test]
+    fn refuses_chunked_bodies_announcing_a_content_length_over_the_limit() {
+        let handshake = Handshake { version: 2, capabilities: vec![Capability::Metadata, Capability::Chunked] };
+        let announce = |length: &str| Message::Metadata(Metadata {
+            status: 200,
+            headers: vec![("Content-Length".to_owned(), length.to_owned())],
+        }).payload().to_vec();
+        let data = frames(&[&announce("12"), b"first "]);
+        // The rest of the body never arrives, it's refused before reading it
+        let mut reader = FrameReader::new(ChunkedStream::new(vec![data]));
+        assert!(matches!(
+            reader.copy_response(&mut Vec::new(), 10, &handshake, |_, _| Ok(())),
+            Err(ProxyError::FrameTooLarge { length: 12, max: 10 }),
+        ));
+        // Like the answer to a HEAD request
+        let data = frames(&[&announce("12"), b""]);
+        let mut reader = FrameReader::new(ChunkedStream::new(vec![data]));
+        assert_eq!(reader.copy_response(&mut Vec::new(), 10, &handshake, |_, _| Ok(())).unwrap().body_length, 0);
+    }
+
+    #[test]
+    fn sends_chunks_with_an_empty_one_last() {
+        let body = vec![7; CHUNK_SIZE + 1];
+        let mut sent = Vec::new();
+        assert_eq!(send_chunks(&mut body.as_slice(), &mut sent).unwrap(), body.len() as u64);
+        let lengths: Vec<_> = read_all(vec![sent], 3).iter().map(Vec::len).collect();
+        assert_eq!(lengths, vec![CHUNK_SIZE, 1, 0]);
+    }
+}
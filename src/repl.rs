@@ -0,0 +1,269 @@
+use std::fs;
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use crate::client::ProxyClient;
+use crate::error::{is_timeout, ProxyError, Result};
+use crate::message::{remote_error, Capability, Message, Metadata};
+
+/// How long `raw` waits for an answer, the proxy may legitimately answer nothing
+const RAW_ANSWER_TIMEOUT: Duration = Duration::from_secs(2);
+const HEX_PREVIEW_BYTES: usize = 32;
+const TEXT_PREVIEW_BYTES: usize = 120;
+
+const HELP: &str = "\
+get <url>     request the URL and show every frame of the response
+raw <text>    send the text as one frame and show the answer,
+              \\n, \\r, \\t, \\\\ and \\xHH are unescaped
+ping          send a PING and wait for the PONG
+save <file>   write the body of the last response into the file
+bye           end the session and quit, same as the end of the input
+help          show this help";
+
+/// A command typed into the REPL
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Get(String),
+    Raw(Vec<u8>),
+    Ping,
+    Save(PathBuf),
+    Bye,
+    Help,
+}
+
+impl Command {
+    /// Blank lines are `None`
+    pub fn parse(line: &str) -> std::result::Result<Option<Command>, String> {
+        let line = line.trim();
+        if line.is_empty() {
+            return Ok(None);
+        }
+        let (name, argument) = match line.split_once(char::is_whitespace) {
+            Some((name, argument)) => (name, argument.trim_start()),
+            None => (line, ""),
+        };
+        let command = match (name, argument) {
+            ("get", "") | ("raw", "") | ("save", "") => return Err(format!("{} needs an argument", name)),
+            ("get", url) => Command::Get(url.to_owned()),
+            ("raw", text) => Command::Raw(unescape(text)?),
+            ("save", path) => Command::Save(PathBuf::from(path)),
+            ("ping", "") => Command::Ping,
+            ("bye", "") => Command::Bye,
+            ("help", "") => Command::Help,
+            ("ping", _) | ("bye", _) | ("help", _) => return Err(format!("{} takes no arguments", name)),
+            _ => return Err(format!("Unknown command {:?}, try help", name)),
+        };
+        Ok(Some(command))
+    }
+}
+
+/// Reads commands from `input` until bye or the end of the input, printing the frames into `out`.
+/// Errors of the commands are printed and the session goes on, only failing to write ends it.
+pub fn run<R: BufRead, W: Write>(client: ProxyClient, input: R, mut out: W) -> Result<()> {
+    let handshake = client.handshake();
+    let capabilities: Vec<_> = handshake.capabilities.iter().map(Capability::to_string).collect();
+    writeln!(out, "Protocol version {}, capabilities: {}", handshake.version, capabilities.join(", "))?;
+    let mut session = Session { client: Some(client), last_body: Vec::new(), out };
+    let mut lines = input.lines();
+    while session.client.is_some() {
+        write!(session.out, "> ")?;
+        session.out.flush()?;
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => {
+                writeln!(session.out)?;
+                session.execute(Command::Bye)?;
+                break;
+            }
+        };
+        match Command::parse(&line) {
+            Ok(Some(command)) => session.execute(command)?,
+            Ok(None) => {}
+            Err(message) => writeln!(session.out, "{}", message)?,
+        }
+    }
+    Ok(())
+}
+
+struct Session<W> {
+    /// Taken by bye
+    client: Option<ProxyClient>,
+    /// Body of the last response, for save
+    last_body: Vec<u8>,
+    out: W,
+}
+
+impl<W: Write> Session<W> {
+    fn execute(&mut self, command: Command) -> Result<()> {
+        let result = match command {
+            Command::Get(url) => self.get(&url),
+            Command::Raw(payload) => self.raw(&payload),
+            Command::Ping => self.ping(),
+            Command::Save(path) => self.save(path),
+            Command::Bye => self.bye(),
+            Command::Help => writeln!(self.out, "{}", HELP).map_err(ProxyError::from),
+        };
+        if let Err(err) = result {
+            writeln!(self.out, "Error: {}", err)?;
+        }
+        Ok(())
+    }
+
+    /// Reads the frames the way the client would: the optional metadata,
+    /// then the body, which goes on until an empty frame when chunked
+    fn get(&mut self, url: &str) -> Result<()> {
+        let client = self.client.as_mut().expect("Only called before bye");
+        let handshake = client.handshake().clone();
+        let started = Instant::now();
+        let payload = Message::Get { url: url.to_owned() }.payload().into_owned();
+        client.send_frame(&payload)?;
+        writeln!(self.out, "-> {} bytes", payload.len())?;
+        self.last_body.clear();
+        let mut metadata_seen = false;
+        let mut in_body = false;
+        for index in 1.. {
+            let frame = client.read_frame(None)?;
+            print_frame(&mut self.out, index, &frame, started.elapsed())?;
+            // From version 2 on the frame after the metadata is always the body
+            if !in_body && (!metadata_seen || handshake.is_legacy()) {
+                if handshake.supports(Capability::Errors) && remote_error(&frame).is_some() {
+                    break;
+                }
+                if handshake.supports(Capability::Metadata) && !metadata_seen && Metadata::parse(&frame).is_some() {
+                    metadata_seen = true;
+                    continue;
+                }
+            }
+            in_body = true;
+            let last = frame.is_empty() || !handshake.supports(Capability::Chunked);
+            self.last_body.extend(frame);
+            if last {
+                break;
+            }
+        }
+        writeln!(self.out, "Body: {} bytes in {}", self.last_body.len(), format_duration(started.elapsed()))?;
+        Ok(())
+    }
+
+    fn raw(&mut self, payload: &[u8]) -> Result<()> {
+        let client = self.client.as_mut().expect("Only called before bye");
+        let started = Instant::now();
+        client.send_frame(payload)?;
+        writeln!(self.out, "-> {} bytes", payload.len())?;
+        match client.read_frame(Some(RAW_ANSWER_TIMEOUT)) {
+            Ok(frame) => {
+                print_frame(&mut self.out, 1, &frame, started.elapsed())?;
+                self.last_body = frame;
+            }
+            Err(ProxyError::Io(err)) if is_timeout(&err) => {
+                writeln!(self.out, "No answer in {}", format_duration(started.elapsed()))?;
+            }
+            Err(err) => return Err(err),
+        }
+        Ok(())
+    }
+
+    fn ping(&mut self) -> Result<()> {
+        let round_trip = self.client.as_mut().expect("Only called before bye").ping()?;
+        writeln!(self.out, "PONG in {}", format_duration(round_trip))?;
+        Ok(())
+    }
+
+    fn save(&mut self, path: PathBuf) -> Result<()> {
+        fs::write(&path, &self.last_body)?;
+        writeln!(self.out, "Saved {} bytes into {}", self.last_body.len(), path.display())?;
+        Ok(())
+    }
+
+    fn bye(&mut self) -> Result<()> {
+        let started = Instant::now();
+        self.client.take().expect("Only called before bye").bye()?;
+        writeln!(self.out, "BYE in {}", format_duration(started.elapsed()))?;
+        Ok(())
+    }
+}
+
+/// The length, the time since the request, and the start of the frame both in hex and as text
+fn print_frame<W: Write>(out: &mut W, index: usize, frame: &[u8], elapsed: Duration) -> Result<()> {
+    writeln!(out, "<- frame {}: {} bytes after {}", index, frame.len(), format_duration(elapsed))?;
+    if frame.is_empty() {
+        return Ok(());
+    }
+    writeln!(out, "   hex  {}", hex_preview(frame))?;
+    writeln!(out, "   utf8 {}", text_preview(frame))?;
+    Ok(())
+}
+
+fn hex_preview(frame: &[u8]) -> String {
+    let hex: Vec<_> = frame.iter().take(HEX_PREVIEW_BYTES).map(|byte| format!("{:02x}", byte)).collect();
+    let ellipsis = if frame.len() > HEX_PREVIEW_BYTES { " ..." } else { "" };
+    format!("{}{}", hex.join(" "), ellipsis)
+}
+
+/// Quoted with the escapes of a Rust string, so line breaks stay on one line
+fn text_preview(frame: &[u8]) -> String {
+    let text = String::from_utf8_lossy(&frame[..frame.len().min(TEXT_PREVIEW_BYTES)]);
+    let ellipsis = if frame.len() > TEXT_PREVIEW_BYTES { " ..." } else { "" };
+    format!("{:?}{}", text, ellipsis)
+}
+
+fn format_duration(duration: Duration) -> String {
+    format!("{:.2}ms", duration.as_secs_f64() * 1000.0)
+}
+
+/// Turns \n, \r, \t, \\ and \xHH into the bytes, so multi-line frames can be typed on one line
+fn unescape(text: &str) -> std::result::Result<Vec<u8>, String> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buffer = [0; 4];
+            bytes.extend(c.encode_utf8(&mut buffer).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('n') => bytes.push(b'\n'),
+            Some('r') => bytes.push(b'\r'),
+            Some('t') => bytes.push(b'\t'),
+            Some('\\') => bytes.push(b'\\'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                match u8::from_str_radix(&hex, 16) {
+                    Ok(byte) if hex.len() == 2 => bytes.push(byte),
+                    _ => return Err(format!("Invalid escape \\x{}", hex)),
+                }
+            }
+            Some(other) => return Err(format!("Invalid escape \\{}", other)),
+            None => return Err("Trailing backslash".to_owned()),
+        }
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_commands() {
+        assert_eq!(Command::parse("  get http://x/a b "), Ok(Some(Command::Get("http://x/a b".to_owned()))));
+        assert_eq!(Command::parse("raw REQ:POST http://x/\\nA: b\\x00"),
+                   Ok(Some(Command::Raw(b"REQ:POST http://x/\nA: b\0".to_vec()))));
+        assert_eq!(Command::parse("save out.bin"), Ok(Some(Command::Save(PathBuf::from("out.bin")))));
+        assert_eq!(Command::parse("ping"), Ok(Some(Command::Ping)));
+        assert_eq!(Command::parse(""), Ok(None));
+        assert!(Command::parse("get").is_err());
+        assert!(Command::parse("bye now").is_err());
+        assert!(Command::parse("raw \\x4").is_err());
+        assert!(Command::parse("fetch http://x/").is_err());
+    }
+
+    #[test]
+    fn previews_the_start_of_the_frame() {
+        assert_eq!(hex_preview(b"META:200\n"), "4d 45 54 41 3a 32 30 30 0a");
+        assert_eq!(text_preview(b"META:200\n"), "\"META:200\\n\"");
+        let long = vec![b'a'; 200];
+        assert!(hex_preview(&long).ends_with("61 ..."));
+        assert_eq!(text_preview(&long), format!("{:?} ...", "a".repeat(TEXT_PREVIEW_BYTES)));
+    }
+}
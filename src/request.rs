@@ -0,0 +1,151 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+use crate::error::{ProxyError, Result};
+use crate::message::{Capability, Handshake, Message, RequestHead};
+use crate::protocol::{send_bytes, send_stream};
+
+/// Methods that can be repeated without changing the result,
+/// only these are retried once the request was sent
+const IDEMPOTENT_METHODS: [&str; 6] = ["GET", "HEAD", "PUT", "DELETE", "OPTIONS", "TRACE"];
+
+/// A request to forward through the proxy
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: RequestBody,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum RequestBody {
+    #[default]
+    Empty,
+    Bytes(Vec<u8>),
+    /// Streamed from the file, so big uploads aren't loaded into memory.
+    /// It's opened again for every attempt.
+    File(PathBuf),
+}
+
+impl Request {
+    pub fn new(method: &str, url: &str) -> Request {
+        Request {
+            method: method.to_ascii_uppercase(),
+            url: url.to_owned(),
+            headers: Vec::new(),
+            body: RequestBody::Empty,
+        }
+    }
+
+    pub fn get(url: &str) -> Request {
+        Request::new("GET", url)
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Request {
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    pub fn body(mut self, body: RequestBody) -> Request {
+        self.body = body;
+        self
+    }
+
+    pub fn is_idempotent(&self) -> bool {
+        IDEMPOTENT_METHODS.contains(&self.method.as_str())
+    }
+
+    /// A plain GET goes out in the original "GET:<url>" form, so older proxies still understand it
+    pub fn send<S: Write>(&self, socket: &mut S, handshake: &Handshake) -> Result<()> {
+        if self.method == "GET" && self.headers.is_empty() && self.body == RequestBody::Empty {
+            return Message::Get { url: self.url.clone() }.send(socket);
+        }
+        if !handshake.supports(Capability::Requests) {
+            return Err(ProxyError::InvalidRequest(format!("The proxy only supports plain GET requests, not {}", self.method)));
+        }
+        self.validate()?;
+        // Checked before anything is sent, so a bad file doesn't leave half a request behind
+        let file = match &self.body {
+            RequestBody::File(path) => {
+                let file = File::open(path)?;
+                let length = file.metadata()?.len();
+                if length > u32::MAX as u64 {
+                    return Err(ProxyError::FrameTooLarge { length, max: u32::MAX as u64 });
+                }
+                Some((file, length))
+            }
+            _ => None,
+        };
+        let head = RequestHead { method: self.method.clone(), url: self.url.clone(), headers: self.headers.clone() };
+        Message::Request(head).send(socket)?;
+        match (file, &self.body) {
+            (Some((mut file, length)), _) => send_stream(length, &mut file, socket),
+            (None, RequestBody::Bytes(bytes)) => send_bytes(bytes, socket),
+            (None, _) => send_bytes(&[], socket),
+        }
+    }
+
+    /// The head is line based, so line breaks would smuggle in extra headers
+    fn validate(&self) -> Result<()> {
+        if self.method.is_empty() || !self.method.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return Err(ProxyError::InvalidRequest(format!("Invalid method {:?}", self.method)));
+        }
+        if self.url.contains(['\r', '\n']) {
+            return Err(ProxyError::InvalidRequest(format!("Line break in the URL {:?}", self.url)));
+        }
+        for (name, value) in &self.headers {
+            if name.is_empty() || name.contains([':', ' ', '\r', '\n']) || value.contains(['\r', '\n']) {
+                return Err(ProxyError::InvalidRequest(format!("Invalid header {:?}: {:?}", name, value)));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use crate::protocol::FrameReader;
+    use super::*;
+
+    fn sent_frames(request: &Request, count: usize) -> Vec<Message> {
+        let mut sent = Vec::new();
+        request.send(&mut sent, &Handshake::offer()).unwrap();
+        let mut reader = FrameReader::new(Cursor::new(sent));
+        let frames = (0..count).map(|_| reader.read_message(u64::MAX).unwrap()).collect();
+        assert!(matches!(reader.read_message(u64::MAX), Err(ProxyError::UnexpectedEof)));
+        frames
+    }
+
+    #[test]
+    fn plain_gets_keep_the_legacy_frame() {
+        assert_eq!(sent_frames(&Request::get("http://x/"), 1), vec![Message::Get { url: "http://x/".to_owned() }]);
+    }
+
+    #[test]
+    fn sends_the_body_after_the_head() {
+        let request = Request::new("post", "http://x/")
+            .header("Content-Type", "application/json")
+            .body(RequestBody::Bytes(b"{}".to_vec()));
+        let head = RequestHead {
+            method: "POST".to_owned(),
+            url: "http://x/".to_owned(),
+            headers: vec![("Content-Type".to_owned(), "application/json".to_owned())],
+        };
+        assert_eq!(sent_frames(&request, 2), vec![Message::Request(head), Message::Unknown(b"{}".to_vec())]);
+    }
+
+    #[test]
+    fn refuses_line_breaks_in_the_head() {
+        let request = Request::get("http://x/").header("X-Test", "a\r\nHost: y");
+        let mut sent = Vec::new();
+        assert!(matches!(request.send(&mut sent, &Handshake::offer()), Err(ProxyError::InvalidRequest(_))));
+        let request = Request::new("DELETE", "http://x/");
+        let handshake = Handshake { version: 2, capabilities: Vec::new() };
+        assert!(matches!(request.send(&mut sent, &handshake), Err(ProxyError::InvalidRequest(_))));
+        assert!(matches!(request.send(&mut sent, &Handshake::legacy()), Err(ProxyError::InvalidRequest(_))));
+        assert!(sent.is_empty());
+        Request::get("http://x/").send(&mut sent, &Handshake::legacy()).unwrap();
+    }
+}
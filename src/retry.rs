@@ -0,0 +1,176 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use crate::error::{is_timeout, ProxyError};
+
+/// Coarse grouping of the errors, used to decide what is worth retrying
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// Connection refused, reset, broken pipe and the like
+    Io,
+    Timeout,
+    /// The proxy closed the connection in the middle of a frame
+    UnexpectedEof,
+    /// The proxy answered with something unexpected
+    Protocol,
+    /// The proxy reported an error for the request
+    Remote,
+    /// Problems on our side that another attempt won't fix
+    Local,
+}
+
+impl FromStr for ErrorClass {
+    type Err = String;
+
+    fn from_str(name: &str) -> std::result::Result<Self, String> {
+        match name {
+            "io" => Ok(ErrorClass::Io),
+            "timeout" => Ok(ErrorClass::Timeout),
+            "eof" => Ok(ErrorClass::UnexpectedEof),
+            "protocol" => Ok(ErrorClass::Protocol),
+            "remote" => Ok(ErrorClass::Remote),
+            "local" => Ok(ErrorClass::Local),
+            _ => Err(format!("Unknown error class {:?}, expected one of io, timeout, eof, protocol, remote, local", name)),
+        }
+    }
+}
+
+impl ProxyError {
+    pub fn class(&self) -> ErrorClass {
+        match self {
+            ProxyError::Timeout { .. } => ErrorClass::Timeout,
+            ProxyError::ConnectFailed { attempts, .. }
+                if !attempts.is_empty() && attempts.iter().all(|(_, err)| is_timeout(err)) => ErrorClass::Timeout,
+            ProxyError::Io(_) | ProxyError::ResolveFailed { .. } | ProxyError::ConnectFailed { .. } => ErrorClass::Io,
+            ProxyError::UnexpectedEof => ErrorClass::UnexpectedEof,
+            ProxyError::HandshakeRejected { .. }
+            | ProxyError::ByeMismatch { .. }
+            | ProxyError::UnexpectedMessage { .. } => ErrorClass::Protocol,
+            ProxyError::Remote { .. } => ErrorClass::Remote,
+            ProxyError::FrameTooLarge { .. }
+            | ProxyError::BadAddress(_)
+            | ProxyError::Tls(_)
+            | ProxyError::InvalidRequest(_)
+            | ProxyError::AuthFailed(_) => ErrorClass::Local,
+        }
+    }
+}
+
+/// How failed attempts are repeated.
+/// The delay doubles after every attempt, starting from `base_delay`
+/// and never exceeding `max_delay`.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Including the first one, so 1 means no retries
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// Fraction of the delay (0 to 1) that is randomly cut off,
+    /// so many clients failing together don't come back together
+    pub jitter: f64,
+    pub retryable: Vec<ErrorClass>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            base_delay: Duration::from_millis(200),
+            max_delay: Duration::from_secs(30),
+            jitter: 0.5,
+            retryable: vec![ErrorClass::Io, ErrorClass::Timeout, ErrorClass::UnexpectedEof],
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// `attempt` is the number of the attempt that just failed, starting from 1
+    pub fn should_retry(&self, err: &ProxyError, attempt: u32) -> bool {
+        attempt < self.max_attempts && self.retryable.contains(&err.class())
+    }
+
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponential = self.base_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)));
+        let capped = exponential.min(self.max_delay);
+        let jitter = self.jitter.clamp(0.0, 1.0) * random_fraction();
+        // Rounding can push the longest durations past the maximum, those stay as they are
+        Duration::try_from_secs_f64(capped.as_secs_f64() * (1.0 - jitter)).map_or(capped, |delay| delay.min(capped))
+    }
+
+    /// The delay before the next attempt, `None` when waiting it out would pass the deadline
+    pub fn delay_before(&self, attempt: u32, deadline: Option<Instant>) -> Option<Duration> {
+        let delay = self.delay(attempt);
+        match deadline {
+            // A delay too long to add to the clock is past any deadline
+            Some(deadline) if Instant::now().checked_add(delay).is_none_or(|resume| resume >= deadline) => None,
+            _ => Some(delay),
+        }
+    }
+}
+
+/// Good enough randomness for the jitter without pulling in a crate for it
+fn random_fraction() -> f64 {
+    let random = RandomState::new().build_hasher().finish();
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use super::*;
+
+    fn policy(jitter: f64) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+            jitter,
+            ..RetryPolicy::default()
+        }
+    }
+
+    #[test]
+    fn doubles_the_delay_up_to_the_cap() {
+        let policy = policy(0.0);
+        assert_eq!(policy.delay(1), Duration::from_millis(100));
+        assert_eq!(policy.delay(2), Duration::from_millis(200));
+        assert_eq!(policy.delay(4), Duration::from_millis(800));
+        assert_eq!(policy.delay(5), Duration::from_secs(1));
+        assert_eq!(policy.delay(u32::MAX), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn jitter_only_shortens_the_delay() {
+        let policy = policy(0.5);
+        for _ in 0..100 {
+            let delay = policy.delay(2);
+            assert!(delay >= Duration::from_millis(100) && delay <= Duration::from_millis(200), "{:?}", delay);
+        }
+    }
+
+    #[test]
+    fn retries_the_listed_classes_until_the_last_attempt() {
+        let policy = policy(0.0);
+        let refused = || ProxyError::Io(io::Error::from(io::ErrorKind::ConnectionRefused));
+        assert!(policy.should_retry(&refused(), 1));
+        assert!(policy.should_retry(&refused(), 2));
+        assert!(!policy.should_retry(&refused(), 3));
+        assert!(!policy.should_retry(&ProxyError::Remote { code: 404, message: String::new() }, 1));
+        assert!(!policy.should_retry(&ProxyError::InvalidRequest(String::new()), 1));
+        let remote = RetryPolicy { retryable: vec![ErrorClass::Remote], ..policy };
+        assert!(remote.should_retry(&ProxyError::Remote { code: 503, message: String::new() }, 1));
+        assert!(!remote.should_retry(&refused(), 1));
+    }
+
+    #[test]
+    fn doesnt_wait_past_the_deadline() {
+        let policy = policy(0.0);
+        assert_eq!(policy.delay_before(1, None), Some(Duration::from_millis(100)));
+        assert_eq!(policy.delay_before(1, Some(Instant::now() + Duration::from_secs(10))), Some(Duration::from_millis(100)));
+        assert_eq!(policy.delay_before(1, Some(Instant::now() + Duration::from_millis(50))), None);
+        let endless = RetryPolicy { base_delay: Duration::MAX, max_delay: Duration::MAX, ..policy };
+        assert_eq!(endless.delay_before(1, Some(Instant::now() + Duration::from_secs(10))), None);
+    }
+}
@@ -0,0 +1,176 @@
+use std::fs::File;
+use std::io::{BufReader, ErrorKind};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::ring;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{ClientConnection, DigitallySignedStruct, RootCertStore, SignatureScheme, StreamOwned};
+use sha2::{Digest, Sha256};
+use crate::error::{ProxyError, Result};
+
+/// Settings for talking to a TLS-terminating proxy
+#[derive(Debug, Clone, Default)]
+pub struct TlsConfig {
+    /// PEM bundle of the trusted CAs, the webpki roots are used when missing
+    pub ca_file: Option<PathBuf>,
+    /// Name to send in SNI and to verify the certificate against,
+    /// defaults to the host of the proxy server address
+    pub server_name: Option<String>,
+    /// PEM certificate chain and private key for client authentication
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+    /// SHA-256 digests of the accepted server certificates.
+    /// When not empty, the certificate has to match one of them. With a `ca_file` it has
+    /// to pass the usual verification too, without one a matching digest is enough,
+    /// so a self-signed certificate can be trusted by its digest alone.
+    pub pinned_sha256: Vec<[u8; 32]>,
+}
+
+pub(crate) type TlsStream = StreamOwned<ClientConnection, TcpStream>;
+
+/// Wraps the connected socket and completes the TLS handshake
+pub(crate) fn wrap(socket: TcpStream, config: &TlsConfig, proxy_server_address: &str) -> Result<TlsStream> {
+    let server_name = config.server_name.clone()
+        .unwrap_or_else(|| host_of(proxy_server_address).to_owned());
+    let server_name = ServerName::try_from(server_name)
+        .map_err(|err| ProxyError::Tls(format!("Invalid server name: {}", err)))?;
+    let connection = ClientConnection::new(Arc::new(client_config(config)?), server_name)
+        .map_err(|err| ProxyError::Tls(err.to_string()))?;
+    let mut stream = StreamOwned::new(connection, socket);
+    while stream.conn.is_handshaking() {
+        // rustls reports rejected certificates and alerts as InvalidData,
+        // those are reported as TLS errors so they aren't retried like network failures
+        stream.conn.complete_io(&mut stream.sock).map_err(|err| match err.kind() {
+            ErrorKind::InvalidData => ProxyError::Tls(err.to_string()),
+            _ => ProxyError::Io(err),
+        })?;
+    }
+    Ok(stream)
+}
+
+fn client_config(config: &TlsConfig) -> Result<rustls::ClientConfig> {
+    let provider = Arc::new(ring::default_provider());
+    let mut roots = RootCertStore::empty();
+    match &config.ca_file {
+        Some(ca_file) => {
+            for certificate in load_certificates(ca_file)? {
+                roots.add(certificate).map_err(|err| ProxyError::Tls(err.to_string()))?;
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+    let webpki_verifier = WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+        .build()
+        .map_err(|err| ProxyError::Tls(err.to_string()))?;
+    let builder = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|err| ProxyError::Tls(err.to_string()))?;
+    let builder = if config.pinned_sha256.is_empty() {
+        builder.with_webpki_verifier(webpki_verifier)
+    } else {
+        builder.dangerous().with_custom_certificate_verifier(Arc::new(PinnedVerifier {
+            inner: webpki_verifier,
+            pins: config.pinned_sha256.clone(),
+            verify_chain: config.ca_file.is_some(),
+        }))
+    };
+    match (&config.client_cert, &config.client_key) {
+        (Some(cert), Some(key)) => builder
+            .with_client_auth_cert(load_certificates(cert)?, load_private_key(key)?)
+            .map_err(|err| ProxyError::Tls(err.to_string())),
+        (None, None) => Ok(builder.with_no_client_auth()),
+        _ => Err(ProxyError::Tls("Client certificate and key have to be given together".to_owned())),
+    }
+}
+
+pub fn load_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certificates = rustls_pemfile::certs(&mut reader).collect::<std::io::Result<Vec<_>>>()?;
+    if certificates.is_empty() {
+        return Err(ProxyError::Tls(format!("No certificates found in {}", path.display())));
+    }
+    Ok(certificates)
+}
+
+pub fn load_private_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| ProxyError::Tls(format!("No private key found in {}", path.display())))
+}
+
+/// Parses the hex SHA-256 digest of a certificate, colons are allowed between the bytes
+pub fn parse_pin(pin: &str) -> std::result::Result<[u8; 32], String> {
+    let hex: String = pin.chars().filter(|c| *c != ':').collect();
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err(format!("{:?} is not a hex SHA-256 digest", pin));
+    }
+    let mut digest = [0; 32];
+    for (index, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[index * 2..index * 2 + 2], 16)
+            .map_err(|_| format!("{:?} is not a hex SHA-256 digest", pin))?;
+    }
+    Ok(digest)
+}
+
+/// "host:port" and "[v6]:port" without the port
+fn host_of(proxy_server_address: &str) -> &str {
+    let host = match proxy_server_address.rfind(':') {
+        Some(index) => &proxy_server_address[..index],
+        None => proxy_server_address,
+    };
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+/// The check against the pinned digests, plus the usual webpki verification when `verify_chain`.
+/// The handshake signatures are always verified.
+#[derive(Debug)]
+struct PinnedVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    pins: Vec<[u8; 32]>,
+    verify_chain: bool,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        if self.verify_chain {
+            self.inner.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+        }
+        let digest: [u8; 32] = Sha256::digest(end_entity.as_ref()).into();
+        if !self.pins.contains(&digest) {
+            return Err(rustls::Error::General("The server certificate doesn't match the pinned digests".to_owned()));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
@@ -0,0 +1,77 @@
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+#[cfg(feature = "tls")]
+use crate::tls::TlsStream;
+
+/// The connection to the proxy, the protocol code only sees `Read + Write`
+pub(crate) enum Transport {
+    Tcp(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsStream>),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Transport {
+    /// Whether secrets can be sent as they are, nobody else can read a TLS stream or a Unix socket
+    pub(crate) fn is_private(&self) -> bool {
+        !matches!(self, Transport::Tcp(_))
+    }
+
+    pub(crate) fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Transport::Tcp(socket) => socket.set_read_timeout(timeout),
+            #[cfg(feature = "tls")]
+            Transport::Tls(stream) => stream.sock.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Transport::Unix(socket) => socket.set_read_timeout(timeout),
+        }
+    }
+
+    pub(crate) fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Transport::Tcp(socket) => socket.set_write_timeout(timeout),
+            #[cfg(feature = "tls")]
+            Transport::Tls(stream) => stream.sock.set_write_timeout(timeout),
+            #[cfg(unix)]
+            Transport::Unix(socket) => socket.set_write_timeout(timeout),
+        }
+    }
+}
+
+impl Read for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Transport::Tcp(socket) => socket.read(buf),
+            #[cfg(feature = "tls")]
+            Transport::Tls(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Transport::Unix(socket) => socket.read(buf),
+        }
+    }
+}
+
+impl Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Transport::Tcp(socket) => socket.write(buf),
+            #[cfg(feature = "tls")]
+            Transport::Tls(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Transport::Unix(socket) => socket.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Transport::Tcp(socket) => socket.flush(),
+            #[cfg(feature = "tls")]
+            Transport::Tls(stream) => stream.flush(),
+            #[cfg(unix)]
+            Transport::Unix(socket) => socket.flush(),
+        }
+    }
+}